@@ -0,0 +1,427 @@
+/* * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * *
+ *                                                                           *
+ *  Copyright  (C)  2019-2024  Christian Krause                              *
+ *                                                                           *
+ *  Christian Krause  <christian.krause@idiv.de>                             *
+ *                                                                           *
+ * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * *
+ *                                                                           *
+ *  This file is part of mmdu.                                               *
+ *                                                                           *
+ *  mmdu is free software: you can redistribute it and/or modify             *
+ *  it under the terms of the GNU General Public License as published by     *
+ *  the Free Software Foundation, either version 3 of the license, or any    *
+ *  later version.                                                           *
+ *                                                                           *
+ *  mmdu is distributed in the hope that it will be useful, but              *
+ *  WITHOUT ANY WARRANTY; without even the implied warranty of               *
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU         *
+ *  General Public License for more details.                                 *
+ *                                                                           *
+ *  You should have received a copy of the GNU General Public License along  *
+ *  with mmdu. If not, see <http://www.gnu.org/licenses/>.                   *
+ *                                                                           *
+ * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * */
+
+use std::cmp::Reverse;
+use std::io::{self, Write};
+
+use anyhow::{Context, Result};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::style::{Attribute, Print, SetAttribute};
+use crossterm::terminal::{self, ClearType};
+use crossterm::{cursor, execute, queue};
+
+use crate::config::{Browse, Config, CountMode, Sort};
+use crate::output::{bar, format_size, percent};
+use crate::usage::{self, Acc, Tree};
+
+const HELP: &str = "up/down: select  right/enter: open  left/backspace: back  \
+     s/i/n/m: sort by size/inodes/name/mtime  q: quit";
+
+pub fn run(browse: &Browse, config: &Config) -> Result<()> {
+    let (tree, config) =
+        usage::load(&browse.report, config).with_context(|| {
+            format!("loading report {}", browse.report.display())
+        })?;
+
+    let mut browser = Browser::new(&tree, Tree::ROOT, &config, browse.sort);
+
+    terminal::enable_raw_mode().context("enabling raw mode of terminal")?;
+
+    let mut stdout = io::stdout();
+    let result =
+        execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)
+            .and_then(|()| interact(&mut stdout, &mut browser));
+
+    // restore the terminal regardless of errors
+    let _ = execute!(stdout, terminal::LeaveAlternateScreen, cursor::Show);
+    let _ = terminal::disable_raw_mode();
+
+    result.context("browsing report")
+}
+
+fn interact(stdout: &mut impl Write, browser: &mut Browser) -> io::Result<()> {
+    loop {
+        let (width, height) = terminal::size()?;
+        let lines = browser.render(width.into(), height.into());
+
+        queue!(stdout, terminal::Clear(ClearType::All))?;
+
+        for (row, line) in (0..).zip(&lines) {
+            queue!(stdout, cursor::MoveTo(0, row))?;
+
+            if Some(usize::from(row)) == browser.selected_row() {
+                queue!(
+                    stdout,
+                    SetAttribute(Attribute::Reverse),
+                    Print(line),
+                    SetAttribute(Attribute::Reset)
+                )?;
+            } else {
+                queue!(stdout, Print(line))?;
+            }
+        }
+
+        stdout.flush()?;
+
+        if let Event::Key(key) = event::read()? {
+            if key.kind == KeyEventKind::Press && !browser.key(key.code) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+// browser
+// ----------------------------------------------------------------------------
+
+/// State of the browser, independent of the terminal.
+struct Browser<'a> {
+    tree: &'a Tree,
+    config: &'a Config,
+    sort: Sort,
+
+    /// The directories from the DIR to the current one.
+    path: Vec<u32>,
+
+    /// The children of the current directory, in order.
+    entries: Vec<u32>,
+
+    /// Index of the selected entry.
+    selected: usize,
+
+    /// Index of the first visible entry.
+    offset: usize,
+}
+
+impl<'a> Browser<'a> {
+    fn new(tree: &'a Tree, dir: u32, config: &'a Config, sort: Sort) -> Self {
+        let mut browser = Self {
+            tree,
+            config,
+            sort,
+            path: vec![dir],
+            entries: vec![],
+            selected: 0,
+            offset: 0,
+        };
+
+        browser.refresh();
+        browser
+    }
+
+    fn current(&self) -> u32 {
+        self.path[self.path.len() - 1]
+    }
+
+    /// Collects and sorts the entries of the current directory.
+    fn refresh(&mut self) {
+        let tree = self.tree;
+
+        self.entries = tree.children(self.current()).collect();
+
+        // largest or newest first, ties by name as the children are ordered
+        match self.sort {
+            Sort::Bytes => {
+                self.entries
+                    .sort_by_key(|node| Reverse(tree[*node].acc.bytes));
+            }
+            Sort::Inodes => {
+                self.entries
+                    .sort_by_key(|node| Reverse(tree[*node].acc.inodes));
+            }
+            Sort::Time => {
+                self.entries
+                    .sort_by_key(|node| Reverse(tree[*node].acc.time));
+            }
+            Sort::Name => {}
+        }
+    }
+
+    /// Handles a key, returns `false` to quit.
+    fn key(&mut self, code: KeyCode) -> bool {
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Up | KeyCode::Char('k') => {
+                self.selected = self.selected.saturating_sub(1);
+            }
+            KeyCode::Down | KeyCode::Char('j') => {
+                let last = self.entries.len().saturating_sub(1);
+                self.selected = (self.selected + 1).min(last);
+            }
+            KeyCode::Home => self.selected = 0,
+            KeyCode::End => {
+                self.selected = self.entries.len().saturating_sub(1);
+            }
+            KeyCode::Right | KeyCode::Enter | KeyCode::Char('l') => {
+                self.open();
+            }
+            KeyCode::Left | KeyCode::Backspace | KeyCode::Char('h') => {
+                self.back();
+            }
+            KeyCode::Char('s') => self.sort_by(Sort::Bytes),
+            KeyCode::Char('i') => self.sort_by(Sort::Inodes),
+            KeyCode::Char('m') => self.sort_by(Sort::Time),
+            KeyCode::Char('n') => self.sort_by(Sort::Name),
+            _ => {}
+        }
+
+        true
+    }
+
+    /// Enters the selected entry if it is a directory with content.
+    fn open(&mut self) {
+        let Some(&node) = self.entries.get(self.selected) else {
+            return;
+        };
+
+        if self.tree.children(node).next().is_some() {
+            self.path.push(node);
+            self.refresh();
+            self.selected = 0;
+            self.offset = 0;
+        }
+    }
+
+    /// Returns to the parent directory, selecting the directory left.
+    fn back(&mut self) {
+        if self.path.len() > 1 {
+            let left = self.path.pop();
+            self.refresh();
+            self.select(left);
+        }
+    }
+
+    fn sort_by(&mut self, sort: Sort) {
+        let selected = self.entries.get(self.selected).copied();
+        self.sort = sort;
+        self.refresh();
+        self.select(selected);
+    }
+
+    fn select(&mut self, node: Option<u32>) {
+        self.selected = node
+            .and_then(|node| self.entries.iter().position(|n| *n == node))
+            .unwrap_or(0);
+    }
+
+    /// Returns the screen row of the selected entry of the last rendering.
+    fn selected_row(&self) -> Option<usize> {
+        (!self.entries.is_empty()).then(|| self.selected - self.offset + 1)
+    }
+
+    /// Returns the lines of a screen of `width` by `height` characters: the
+    /// current directory, the visible entries and the keys.
+    fn render(&mut self, width: usize, height: usize) -> Vec<String> {
+        let rows = height.saturating_sub(2).max(1);
+
+        // scroll the selected entry into view
+        if self.selected < self.offset {
+            self.offset = self.selected;
+        } else if self.selected >= self.offset + rows {
+            self.offset = self.selected + 1 - rows;
+        }
+
+        let tree = self.tree;
+        let current = tree[self.current()].acc;
+
+        let mut lines = Vec::with_capacity(rows + 2);
+
+        lines.push(format!(
+            "{}  {}  {} inodes",
+            tree.path(self.current()).display(),
+            self.size(&current),
+            current.inodes
+        ));
+
+        for &node in self.entries.iter().skip(self.offset).take(rows) {
+            lines.push(self.entry(node, &current));
+        }
+
+        lines.push(String::from(HELP));
+
+        lines
+            .into_iter()
+            .map(|line| line.chars().take(width).collect())
+            .collect()
+    }
+
+    fn entry(&self, node: u32, current: &Acc) -> String {
+        let tree = self.tree;
+        let acc = tree[node].acc;
+
+        // relative to the current directory by the sort order
+        let (part, whole) = match (self.sort, self.config.count_mode) {
+            (Sort::Inodes, _) | (Sort::Time, CountMode::Inodes) => {
+                (acc.inodes, current.inodes)
+            }
+            _ => (acc.bytes, current.bytes),
+        };
+
+        let time = acc
+            .time
+            .map_or_else(|| String::from("-"), |time| time.to_string());
+
+        let name = tree[node].name().to_string_lossy();
+        let slash = if tree.children(node).next().is_some() {
+            "/"
+        } else {
+            ""
+        };
+
+        format!(
+            "{:>9} {:>9} {:>6} [{}] {time:>16}  {name}{slash}",
+            self.size(&acc),
+            acc.inodes,
+            percent(part, whole),
+            bar(part, whole),
+        )
+    }
+
+    fn size(&self, acc: &Acc) -> String {
+        let bytes = self.config.byte_mode.bytes(acc.bytes);
+        format_size(bytes, &self.config.unit)
+    }
+}
+
+// ----------------------------------------------------------------------------
+// tests
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use indoc::indoc;
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    const SOURCE: &str = indoc! {"
+        1 1 0  4096 1 drwxr-xr-x 2024-01-01 00:00:00 -- /data/test
+        2 1 0  1024 1 -rw-r--r-- 2024-03-01 12:00:00 -- /data/test/foo
+        3 1 0  4096 1 drwxr-xr-x 2024-01-01 00:00:00 -- /data/test/a
+        4 1 0  2048 1 -rw-r--r-- 2024-02-01 00:00:00 -- /data/test/a/bar
+        5 1 0  1024 1 -rw-r--r-- 2024-02-01 00:00:00 -- /data/test/a/baz
+    "};
+
+    fn load(config: &Config) -> (Tree, Config) {
+        let mut report = NamedTempFile::new().unwrap();
+        report
+            .write_all(
+                b"# mmdu report: FILE_SIZE MODIFICATION_TIME -- /data/test\n",
+            )
+            .unwrap();
+        report.write_all(SOURCE.as_bytes()).unwrap();
+
+        usage::load(report.path(), config).unwrap()
+    }
+
+    fn names(browser: &Browser) -> Vec<String> {
+        browser
+            .entries
+            .iter()
+            .map(|node| browser.tree[*node].name().to_string_lossy().into())
+            .collect()
+    }
+
+    #[test]
+    fn navigate() {
+        let (tree, config) = load(&Config::from_args(&[]));
+        let mut browser =
+            Browser::new(&tree, Tree::ROOT, &config, Sort::Bytes);
+
+        assert_eq!(vec!["a", "foo"], names(&browser));
+
+        // files cannot be opened
+        assert!(browser.key(KeyCode::Down));
+        assert!(browser.key(KeyCode::Enter));
+        assert_eq!(Path::new("/data/test"), tree.path(browser.current()));
+
+        assert!(browser.key(KeyCode::Up));
+        assert!(browser.key(KeyCode::Right));
+        assert_eq!(Path::new("/data/test/a"), tree.path(browser.current()));
+        assert_eq!(vec!["bar", "baz"], names(&browser));
+
+        // the directory left is selected again
+        assert!(browser.key(KeyCode::Left));
+        assert_eq!(Path::new("/data/test"), tree.path(browser.current()));
+        assert_eq!(0, browser.selected);
+
+        // the DIR is never left
+        assert!(browser.key(KeyCode::Left));
+        assert_eq!(Path::new("/data/test"), tree.path(browser.current()));
+
+        assert!(!browser.key(KeyCode::Char('q')));
+    }
+
+    #[test]
+    fn sort() {
+        let (tree, config) = load(&Config::from_args(&[]));
+        let mut browser =
+            Browser::new(&tree, Tree::ROOT, &config, Sort::Bytes);
+
+        assert_eq!(vec!["a", "foo"], names(&browser));
+
+        // the selection is kept
+        browser.key(KeyCode::Down);
+        browser.key(KeyCode::Char('m'));
+        assert_eq!(vec!["foo", "a"], names(&browser));
+        assert_eq!(0, browser.selected);
+
+        browser.key(KeyCode::Char('i'));
+        assert_eq!(vec!["a", "foo"], names(&browser));
+        assert_eq!(1, browser.selected);
+
+        browser.key(KeyCode::Char('n'));
+        assert_eq!(vec!["a", "foo"], names(&browser));
+    }
+
+    #[test]
+    fn render() {
+        let (tree, config) = load(&Config::from_args(&["--block-size", "1"]));
+        let mut browser =
+            Browser::new(&tree, Tree::ROOT, &config, Sort::Bytes);
+
+        let lines = browser.render(200, 10);
+
+        assert_eq!(
+            vec![
+                "/data/test  12288  5 inodes",
+                "     7168         3  58.3% [#####     ] 2024-02-01 00:00  a/",
+                "     1024         1   8.3% [          ] 2024-03-01 12:00  foo",
+                HELP,
+            ],
+            lines
+        );
+        assert_eq!(Some(1), browser.selected_row());
+
+        // scrolled to the selected entry and truncated
+        browser.key(KeyCode::Down);
+        let lines = browser.render(12, 3);
+        assert_eq!(vec!["/data/test  ", "     1024   ", &HELP[..12]], lines);
+        assert_eq!(Some(1), browser.selected_row());
+    }
+}
@@ -25,6 +25,7 @@
 
 use std::path::PathBuf;
 
+use clap::builder::RangedU64ValueParser;
 use clap::value_parser;
 use clap::{Arg, ArgAction, Command};
 use clap::{crate_description, crate_name, crate_version};
@@ -45,17 +46,30 @@ pub fn build() -> Command {
  directories are read from standard input.",
         );
 
-    let max_depth = Arg::new("max-depth")
-        .short('d')
-        .long("max-depth")
-        .value_name("DEPTH")
-        .help("output sub-directory depth")
+    let threshold = Arg::new("threshold")
+        .short('t')
+        .long("threshold")
+        .value_name("SIZE")
+        .allow_hyphen_values(true)
+        .value_parser(threshold)
+        .help("exclude entries smaller than SIZE")
         .long_help(
-"Show the size of each sub-directory up to the given depth including totals \
- for each super-directory. Setting maximum depth to 0 is equivalent to not \
- specifying it at all.",
-        )
-        .value_parser(value_parser!(usize));
+"Exclude entries smaller than SIZE if positive, or entries greater than SIZE \
+ if negative. SIZE is an integer with an optional unit: K, M, G, T, P, E \
+ (powers of 1024) or KB, MB, GB, TB, PB, EB (powers of 1000). With --inodes, \
+ the threshold applies to the inode count instead of the block usage. With \
+ --both, it applies to the block usage only, the inode count is not filtered.",
+        );
+
+    let total = Arg::new("total")
+        .short('c')
+        .long("total")
+        .action(ArgAction::SetTrue)
+        .help("produce a grand total")
+        .long_help(
+"Produce a grand total of all DIRs. Hard linked objects are counted only once \
+ in the grand total, unless --count-links is used.",
+        );
 
     let count_links = Arg::new("count-links")
         .short('l')
@@ -68,6 +82,18 @@ pub fn build() -> Command {
  DIR and once for every sub-directory if max depth is used."
         );
 
+    let dedupe_across_dirs = Arg::new("dedupe-across-dirs")
+        .long("dedupe-across-dirs")
+        .action(ArgAction::SetTrue)
+        .conflicts_with("count-links")
+        .hide_short_help(true)
+        .long_help(
+"Count hard linked objects only in the first DIR they are found in, like \
+ `du` does. By default, they are counted once for every DIR, so the numbers \
+ of the DIRs may not add up to the grand total. The hard linked objects \
+ counted so far use the memory of the grand total, see --link-memory.",
+        );
+
     let kb_allocated = Arg::new("kb-allocated")
         .long("kb-allocated")
         .action(ArgAction::SetTrue)
@@ -95,21 +121,69 @@ pub fn build() -> Command {
         .max_term_width(100)
         .disable_help_flag(true)
         .disable_version_flag(true)
+        .args_conflicts_with_subcommands(true)
         .arg(dir)
         .args(output_fields())
+        .args(output_format())
+        .args(units())
+        .args(depth())
         .args(filter())
         .args(mmapplypolicy())
-        .arg(max_depth)
+        .args(performance())
+        .args(parse_errors())
+        .arg(threshold)
+        .arg(total)
         .arg(count_links)
+        .arg(dedupe_across_dirs)
         .arg(kb_allocated)
         .arg(help)
         .arg(version)
+        .subcommand(browse())
         .after_help(
 "Differences to `du`: `mmdu` defaults to summarized and human readable output \
  and uses apparent size, i.e. `FILE_SIZE` as the policy attribute.",
         )
 }
 
+// ----------------------------------------------------------------------------
+// subcommands
+
+fn browse() -> Command {
+    let report = Arg::new("report")
+        .value_name("REPORT")
+        .required(true)
+        .value_parser(value_parser!(PathBuf))
+        .help("saved policy report")
+        .long_help(
+"The policy report saved with --save-report. The policy attribute and the \
+ time are taken from the report, so options like --kb-allocated and --time \
+ are not needed to browse it.",
+        );
+
+    let sort = Arg::new("sort")
+        .long("sort")
+        .value_name("ORDER")
+        .value_parser(["size", "inodes", "name", "mtime"])
+        .default_value("size")
+        .help("initial order of entries")
+        .long_help(
+"The initial order of entries by size, inodes, name or modification time, \
+ like --sort of the DIRs. This can be changed with the keys s, i, n and m.",
+        );
+
+    Command::new("browse")
+        .about("browse a saved report interactively")
+        .long_about(
+"Browse a policy report saved with --save-report interactively, without \
+ scanning again. Directories are entered with the right arrow key or enter \
+ and left with the left arrow key or backspace. The percentage and the bar \
+ of each entry are relative to the current directory.",
+        )
+        .arg(report)
+        .arg(sort)
+        .args(units())
+}
+
 // ----------------------------------------------------------------------------
 // argument groups
 // ----------------------------------------------------------------------------
@@ -139,7 +213,343 @@ fn output_fields() -> Vec<Arg> {
         .long_help("Show both block usage and inode usage.")
         .help_heading("Output Fields");
 
-    vec![block, inodes, both]
+    let time = Arg::new("time")
+        .long("time")
+        .value_name("WORD")
+        .value_parser(["mtime", "atime", "ctime", "crtime"])
+        .num_args(0..=1)
+        .require_equals(true)
+        .default_missing_value("mtime")
+        .help("show time of last modification")
+        .long_help(
+"Show the newest time of any file or directory within each entry. By \
+ default, this is the modification time. Use WORD to show the access time \
+ (atime), the change time (ctime) or the creation time (crtime) instead.",
+        )
+        .help_heading("Output Fields");
+
+    let types = Arg::new("types")
+        .long("types")
+        .action(ArgAction::SetTrue)
+        .help("show inodes by type")
+        .long_help(
+"Show the number of regular files, directories, symbolic links and other \
+ types, like sockets or devices, within each entry.",
+        )
+        .help_heading("Output Fields");
+
+    let allocation = Arg::new("allocation")
+        .long("allocation")
+        .action(ArgAction::SetTrue)
+        .help("show apparent and allocated size")
+        .long_help(
+"Show the apparent size (FILE_SIZE), the allocated size (KB_ALLOCATED) and \
+ their ratio, which reveals sparse files, compression and the overhead of \
+ small files. Both attributes are gathered with a single scan.",
+        )
+        .help_heading("Output Fields");
+
+    let percent = Arg::new("percent")
+        .long("percent")
+        .action(ArgAction::SetTrue)
+        .help("show shares of parent and DIR")
+        .long_help(
+"Show the share of each entry of its parent directory and of the DIR, for \
+ both block usage and inode usage. This is most useful with --max-depth.",
+        )
+        .help_heading("Output Fields");
+
+    vec![block, inodes, both, time, types, allocation, percent]
+}
+
+fn output_format() -> Vec<Arg> {
+    let format = Arg::new("format")
+        .long("format")
+        .value_name("FORMAT")
+        .value_parser(["human", "json", "jsonl", "csv", "tsv"])
+        .default_value("human")
+        .help("output format")
+        .long_help(
+"The output format. With human, sizes are humanized and columns are \
+ separated by tabs. With json, a JSON array of records is printed, with \
+ jsonl, one JSON record per line (JSON Lines). JSON records contain raw \
+ numbers, the byte mode, the filter, the depth and metadata of the scan. \
+ With csv and tsv, a header row is followed by one row per entry, with raw \
+ numbers. In these formats, times are in ISO 8601 without a time zone, i.e. \
+ the local time of the policy engine.",
+        )
+        .help_heading("Output Format");
+
+    let columns = Arg::new("columns")
+        .long("columns")
+        .value_name("COLUMN")
+        .value_parser(["owner", "filter", "depth"])
+        .value_delimiter(',')
+        .action(ArgAction::Append)
+        .hide_short_help(true)
+        .long_help(
+"Additional columns for --format csv or tsv, separated by comma: the owner \
+ of each entry, which is empty if the entry itself is not part of the \
+ report, e.g. with --user, the filter, i.e. --user or --group, and the \
+ depth within the DIR.",
+        )
+        .help_heading("Output Format");
+
+    let tree = Arg::new("tree")
+        .long("tree")
+        .action(ArgAction::SetTrue)
+        .conflicts_with("format")
+        .hide_short_help(true)
+        .long_help(
+"Print the entries of each DIR as a tree. Each entry shows its percentage of \
+ its parent and a bar, the DIR itself is the root. This is most useful with \
+ --max-depth.",
+        )
+        .help_heading("Output Format");
+
+    let null = Arg::new("null")
+        .short('0')
+        .long("null")
+        .action(ArgAction::SetTrue)
+        .conflicts_with("format")
+        .hide_short_help(true)
+        .long_help(
+"End each output line with NUL instead of newline, like `du -0`. Together \
+ with the default --quoting-style, paths can be fed to `xargs -0` safely.",
+        )
+        .help_heading("Output Format");
+
+    let quoting_style = Arg::new("quoting-style")
+        .long("quoting-style")
+        .value_name("WORD")
+        .value_parser(["literal", "shell", "escape", "c"])
+        .default_value("literal")
+        .hide_short_help(true)
+        .long_help(
+"How to quote paths in human output. With literal, the raw bytes of paths \
+ are printed unchanged, even if they are not valid UTF-8. With shell, paths \
+ are put in single quotes if they contain special characters. With escape, \
+ backslashes, spaces, control characters and invalid UTF-8 are escaped like \
+ in C, with c, the path is also put in double quotes.",
+        )
+        .help_heading("Output Format");
+
+    let export_ncdu = Arg::new("export-ncdu")
+        .long("export-ncdu")
+        .value_name("FILE")
+        .value_parser(value_parser!(PathBuf))
+        .hide_short_help(true)
+        .long_help(
+"Additionally write the full tree of the DIR, i.e. every file and directory \
+ regardless of --max-depth, to FILE in the JSON export format of ncdu. The \
+ scan can then be browsed interactively with `ncdu -f FILE`. Hard links are \
+ marked, ncdu counts them only once. Only the first DIR is exported, because \
+ the format contains a single tree, a warning is printed for any other DIR. \
+ This requires memory for every file and directory of the DIR.",
+        )
+        .help_heading("Output Format");
+
+    let save_report = Arg::new("save-report")
+        .long("save-report")
+        .value_name("FILE")
+        .value_parser(value_parser!(PathBuf))
+        .hide_short_help(true)
+        .long_help(
+"Additionally save the policy report of the first DIR to FILE, along with \
+ the DIR and its columns. It can be browsed with `mmdu browse FILE`, \
+ without scanning again or giving the same options.",
+        )
+        .help_heading("Output Format");
+
+    vec![
+        format,
+        columns,
+        tree,
+        null,
+        quoting_style,
+        export_ncdu,
+        save_report,
+    ]
+}
+
+fn units() -> Vec<Arg> {
+    // the unit options override each other
+    let units = [
+        "human-readable",
+        "si",
+        "bytes",
+        "block-size",
+        "kilobytes",
+        "megabytes",
+    ];
+    let others = |unit| units.into_iter().filter(move |other| *other != unit);
+
+    let human_readable = Arg::new("human-readable")
+        .short('h')
+        .long("human-readable")
+        .action(ArgAction::SetTrue)
+        .overrides_with_all(others("human-readable"))
+        .hide_short_help(true)
+        .long_help(
+"Print sizes in human readable format in powers of 1024, e.g. 1.5K. This is \
+ the default.",
+        )
+        .help_heading("Output Format");
+
+    let si = Arg::new("si")
+        .long("si")
+        .action(ArgAction::SetTrue)
+        .overrides_with_all(others("si"))
+        .hide_short_help(true)
+        .long_help(
+"Print sizes in human readable format in powers of 1000, e.g. 1.5k.",
+        )
+        .help_heading("Output Format");
+
+    let bytes = Arg::new("bytes")
+        .short('b')
+        .long("bytes")
+        .action(ArgAction::SetTrue)
+        .overrides_with_all(others("bytes"))
+        .help("print sizes in bytes")
+        .long_help(
+"Print sizes in bytes, equivalent to --block-size=1. Note that, unlike \
+ `du`, apparent size is used anyway, unless --kb-allocated is given.",
+        )
+        .help_heading("Output Format");
+
+    let block_size = Arg::new("block-size")
+        .short('B')
+        .long("block-size")
+        .value_name("SIZE")
+        .value_parser(block_size)
+        .overrides_with_all(others("block-size"))
+        .help("scale sizes by SIZE")
+        .long_help(
+"Print sizes as the number of blocks of SIZE, rounded up. SIZE is an integer \
+ with an optional unit like with --threshold. If the integer is omitted, \
+ e.g. -BM, the unit is printed after sizes.",
+        )
+        .help_heading("Output Format");
+
+    let kilobytes = Arg::new("kilobytes")
+        .short('k')
+        .action(ArgAction::SetTrue)
+        .overrides_with_all(others("kilobytes"))
+        .hide_short_help(true)
+        .long_help("Like --block-size=1K.")
+        .help_heading("Output Format");
+
+    let megabytes = Arg::new("megabytes")
+        .short('m')
+        .action(ArgAction::SetTrue)
+        .overrides_with_all(others("megabytes"))
+        .hide_short_help(true)
+        .long_help("Like --block-size=1M.")
+        .help_heading("Output Format");
+
+    vec![human_readable, si, bytes, block_size, kilobytes, megabytes]
+}
+
+fn depth() -> Vec<Arg> {
+    let max_depth = Arg::new("max-depth")
+        .short('d')
+        .long("max-depth")
+        .value_name("DEPTH")
+        .help("output sub-directory depth")
+        .long_help(
+"Show the size of each sub-directory up to the given depth including totals \
+ for each super-directory. Setting maximum depth to 0 is equivalent to not \
+ specifying it at all.",
+        )
+        .value_parser(value_parser!(usize))
+        .help_heading("Depth");
+
+    let all = Arg::new("all")
+        .short('a')
+        .long("all")
+        .action(ArgAction::SetTrue)
+        .requires("max-depth")
+        .help("output files, not just directories")
+        .long_help(
+"Show the size of files up to the maximum depth, not just directories.",
+        )
+        .help_heading("Depth");
+
+    let separate_dirs = Arg::new("separate-dirs")
+        .short('S')
+        .long("separate-dirs")
+        .action(ArgAction::SetTrue)
+        .requires("max-depth")
+        .conflicts_with_all(["percent", "tree"])
+        .help("do not include size of sub-directories")
+        .long_help(
+"For directories, do not include the size of sub-directories, i.e. show only \
+ the size of the directory itself and the files it directly contains. This \
+ cannot be combined with --percent and --tree, as a directory may then be \
+ smaller than its sub-directories.",
+        )
+        .help_heading("Depth");
+
+    let empty_dirs = Arg::new("empty-dirs")
+        .long("empty-dirs")
+        .action(ArgAction::SetTrue)
+        .requires("max-depth")
+        .help("output empty directories")
+        .long_help(
+"Show every directory up to the maximum depth, including empty directories \
+ and the DIR itself. By default, directories without any content are \
+ omitted.",
+        )
+        .help_heading("Depth");
+
+    let sort = Arg::new("sort")
+        .long("sort")
+        .value_name("ORDER")
+        .value_parser(["size", "inodes", "name", "mtime"])
+        .requires("max-depth")
+        .requires_if("mtime", "time")
+        .hide_short_help(true)
+        .long_help(
+"Sort the entries of each DIR by size, inodes, name or modification time. \
+ Size, inodes and time sort largest or newest first, name sorts in path \
+ order, which is the default. Sorting by time requires --time. With --tree, \
+ this sorts the entries within each directory.",
+        )
+        .help_heading("Depth");
+
+    let reverse = Arg::new("reverse")
+        .short('r')
+        .long("reverse")
+        .action(ArgAction::SetTrue)
+        .requires("max-depth")
+        .hide_short_help(true)
+        .long_help("Reverse the order of the entries, see --sort.")
+        .help_heading("Depth");
+
+    let limit = Arg::new("limit")
+        .long("limit")
+        .value_name("N")
+        .value_parser(RangedU64ValueParser::<usize>::new().range(1..))
+        .requires("max-depth")
+        .conflicts_with("tree")
+        .hide_short_help(true)
+        .long_help(
+"Show only the first N entries of each DIR, in the order of --sort and \
+ --reverse. This cannot be combined with --tree, which needs the DIR and all \
+ directories above an entry.",
+        )
+        .help_heading("Depth");
+
+    vec![
+        max_depth,
+        all,
+        separate_dirs,
+        empty_dirs,
+        sort,
+        reverse,
+        limit,
+    ]
 }
 
 fn filter() -> Vec<Arg> {
@@ -202,6 +612,66 @@ fn mmapplypolicy() -> Vec<Arg> {
     vec![nodes, local_work_dir, global_work_dir]
 }
 
+fn performance() -> Vec<Arg> {
+    let link_memory = Arg::new("link-memory")
+        .long("link-memory")
+        .value_name("SIZE")
+        .value_parser(size)
+        .default_value("1G")
+        .hide_short_help(true)
+        .long_help(
+"Memory to use for counting hard linked objects only once. If exceeded, hard \
+ links are spilled to temporary files in the local work directory, see \
+ --mm-s, or the system temporary directory. With --total or \
+ --dedupe-across-dirs, the memory is shared with the hard links of the grand \
+ total. SIZE is an integer with an optional unit like with --threshold.",
+        )
+        .help_heading("Performance");
+
+    let threads = Arg::new("threads")
+        .long("threads")
+        .value_name("N")
+        .value_parser(RangedU64ValueParser::<usize>::new().range(1..))
+        .hide_short_help(true)
+        .long_help(
+"Number of threads to parse the policy report with. Defaults to the number \
+ of available CPUs.",
+        )
+        .help_heading("Performance");
+
+    vec![link_memory, threads]
+}
+
+fn parse_errors() -> Vec<Arg> {
+    let on_parse_error = Arg::new("on-parse-error")
+        .long("on-parse-error")
+        .value_name("MODE")
+        .value_parser(["fail", "skip", "warn"])
+        .default_value("fail")
+        .hide_short_help(true)
+        .long_help(
+"What to do with invalid lines in the policy report. With fail, the DIR is \
+ skipped. With skip, the invalid lines are skipped and a note with their \
+ number is printed to stderr for each DIR, as its output is approximate. The \
+ other output formats contain the number as well. With warn, a warning is \
+ printed for every skipped line as well.",
+        )
+        .help_heading("Parse Errors");
+
+    let max_parse_errors = Arg::new("max-parse-errors")
+        .long("max-parse-errors")
+        .value_name("N")
+        .value_parser(value_parser!(u64))
+        .hide_short_help(true)
+        .long_help(
+            "Skip the DIR anyway if it has more than N invalid lines, see \
+ --on-parse-error. By default, there is no limit.",
+        )
+        .help_heading("Parse Errors");
+
+    vec![on_parse_error, max_parse_errors]
+}
+
 // ----------------------------------------------------------------------------
 // argument validator
 // ----------------------------------------------------------------------------
@@ -220,6 +690,72 @@ fn is_dir(s: &str) -> Result<PathBuf, String> {
     }
 }
 
+/// Parses a size with an optional unit suffix, like `du` does.
+fn size(s: &str) -> Result<u64, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| format!("invalid size: {s}"))?;
+
+    let exponent = match unit.chars().next() {
+        None => 0,
+        Some('k' | 'K') => 1,
+        Some('M') => 2,
+        Some('G') => 3,
+        Some('T') => 4,
+        Some('P') => 5,
+        Some('E') => 6,
+        Some(_) => return Err(format!("invalid unit in size: {s}")),
+    };
+
+    let base: u64 = match unit.get(1..).unwrap_or_default() {
+        "" | "iB" => 1024,
+        "B" => 1000,
+        _ => return Err(format!("invalid unit in size: {s}")),
+    };
+
+    base.checked_pow(exponent)
+        .and_then(|factor| number.checked_mul(factor))
+        .ok_or_else(|| format!("size too large: {s}"))
+}
+
+/// Parses a block size like `du`, where the integer may be omitted. Returns
+/// the size and, if the integer was omitted, the unit to print after sizes.
+fn block_size(s: &str) -> Result<(u64, Option<String>), String> {
+    let (size, suffix) = if s.is_empty() {
+        return Err(String::from("block size must not be empty"));
+    } else if s.starts_with(|c: char| c.is_ascii_digit()) {
+        (self::size(s)?, None)
+    } else {
+        (self::size(&format!("1{s}"))?, Some(s.to_owned()))
+    };
+
+    if size == 0 {
+        Err(format!("block size must not be zero: {s}"))
+    } else {
+        Ok((size, suffix))
+    }
+}
+
+fn threshold(s: &str) -> Result<i64, String> {
+    let (negative, size) =
+        s.strip_prefix('-').map_or((false, s), |size| (true, size));
+
+    let size = self::size(size)?;
+    let size =
+        i64::try_from(size).map_err(|_| format!("size too large: {s}"))?;
+
+    if negative && size == 0 {
+        Err(format!("negative threshold must not be zero: {s}"))
+    } else if negative {
+        Ok(-size)
+    } else {
+        Ok(size)
+    }
+}
+
 // ----------------------------------------------------------------------------
 // tests
 // ----------------------------------------------------------------------------
@@ -230,4 +766,46 @@ mod test {
     fn verify_cli() {
         super::build().debug_assert();
     }
+
+    #[test]
+    fn size() {
+        assert_eq!(Ok(0), super::size("0"));
+        assert_eq!(Ok(42), super::size("42"));
+        assert_eq!(Ok(2048), super::size("2K"));
+        assert_eq!(Ok(2048), super::size("2k"));
+        assert_eq!(Ok(2048), super::size("2KiB"));
+        assert_eq!(Ok(2000), super::size("2KB"));
+        assert_eq!(Ok(1 << 40), super::size("1T"));
+        assert_eq!(Ok(1_000_000), super::size("1MB"));
+
+        assert!(super::size("").is_err());
+        assert!(super::size("K").is_err());
+        assert!(super::size("1X").is_err());
+        assert!(super::size("1Kb").is_err());
+        assert!(super::size("1.5K").is_err());
+        assert!(super::size("16E").is_err());
+    }
+
+    #[test]
+    fn block_size() {
+        assert_eq!(Ok((1, None)), super::block_size("1"));
+        assert_eq!(Ok((4096, None)), super::block_size("4K"));
+        assert_eq!(Ok((1 << 20, Some("M".into()))), super::block_size("M"));
+        assert_eq!(Ok((1000, Some("kB".into()))), super::block_size("kB"));
+
+        assert!(super::block_size("0").is_err());
+        assert!(super::block_size("").is_err());
+        assert!(super::block_size("X").is_err());
+    }
+
+    #[test]
+    fn threshold() {
+        assert_eq!(Ok(1 << 40), super::threshold("1T"));
+        assert_eq!(Ok(-(1 << 20)), super::threshold("-1M"));
+        assert_eq!(Ok(0), super::threshold("0"));
+
+        assert!(super::threshold("-0").is_err());
+        assert!(super::threshold("--1").is_err());
+        assert!(super::threshold("8E").is_err());
+    }
 }
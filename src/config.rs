@@ -23,37 +23,112 @@
  *                                                                           *
  * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * */
 
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::thread;
 
 use anyhow::{Context, Result, anyhow};
 use clap::ArgMatches;
 use libc::{gid_t, uid_t};
 
-#[derive(Debug)]
+// ALLOW these are mostly command-line flags
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Clone, Debug)]
 pub struct Config {
     pub filter: Filter,
     pub count_links: bool,
+    pub dedupe_across_dirs: bool,
     pub max_depth: Option<usize>,
+    pub all: bool,
+    pub separate_dirs: bool,
+    pub empty_dirs: bool,
+    pub sort: Sort,
+    pub reverse: bool,
+    pub limit: Option<usize>,
+    pub threshold: Option<Threshold>,
+    pub total: bool,
+    pub link_memory: u64,
+    pub threads: usize,
+    pub on_parse_error: OnParseError,
+    pub max_parse_errors: Option<u64>,
     pub mm_nodes: Option<String>,
     pub mm_local_work_dir: Option<PathBuf>,
     pub mm_global_work_dir: Option<PathBuf>,
     pub byte_mode: ByteMode,
     pub count_mode: CountMode,
+    pub time_mode: Option<TimeMode>,
+    pub types: bool,
+    pub allocation: bool,
+    pub percent: bool,
+    pub format: Format,
+    pub columns: Columns,
+    pub tree: bool,
+    pub null: bool,
+    pub quoting: Quoting,
+    pub unit: Unit,
+    pub export_ncdu: Option<PathBuf>,
+    pub save_report: Option<PathBuf>,
+    pub browse: Option<Browse>,
 }
 
 impl TryFrom<&ArgMatches> for Config {
     type Error = anyhow::Error;
 
+    // ALLOW one statement per option is easier to comprehend
+    #[allow(clippy::too_many_lines)]
     fn try_from(args: &ArgMatches) -> Result<Self> {
         let filter = Filter::try_from(args)?;
 
         let count_links = args.get_flag("count-links");
 
+        let dedupe_across_dirs = args.get_flag("dedupe-across-dirs");
+
         let max_depth = args
             .get_one::<usize>("max-depth")
             .copied()
             .filter(|depth| *depth > 0);
 
+        let all = args.get_flag("all");
+
+        let separate_dirs = args.get_flag("separate-dirs");
+
+        let empty_dirs = args.get_flag("empty-dirs");
+
+        let sort = args
+            .get_one::<String>("sort")
+            .map_or(Sort::Name, |sort| Sort::from(sort.as_str()));
+
+        let reverse = args.get_flag("reverse");
+
+        let limit = args.get_one::<usize>("limit").copied();
+
+        let threshold = args
+            .get_one::<i64>("threshold")
+            .copied()
+            .and_then(Threshold::new);
+
+        let total = args.get_flag("total");
+
+        let link_memory = args
+            .get_one::<u64>("link-memory")
+            .copied()
+            .expect("link memory has a default value");
+
+        let threads = args
+            .get_one::<usize>("threads")
+            .copied()
+            .unwrap_or_else(|| {
+                thread::available_parallelism().map_or(1, NonZeroUsize::get)
+            });
+
+        let on_parse_error = args
+            .get_one::<String>("on-parse-error")
+            .map(|mode| OnParseError::from(mode.as_str()))
+            .expect("on parse error has a default value");
+
+        let max_parse_errors =
+            args.get_one::<u64>("max-parse-errors").copied();
+
         let mm_nodes = args.get_one::<String>("nodes").cloned();
 
         let mm_local_work_dir =
@@ -70,20 +145,79 @@ impl TryFrom<&ArgMatches> for Config {
 
         let count_mode = CountMode::from(args);
 
+        let time_mode = args
+            .get_one::<String>("time")
+            .map(|time| TimeMode::from(time.as_str()));
+
+        let types = args.get_flag("types");
+
+        let allocation = args.get_flag("allocation");
+
+        let percent = args.get_flag("percent");
+
+        let format = args
+            .get_one::<String>("format")
+            .map(|format| Format::from(format.as_str()))
+            .expect("format has a default value");
+
+        let columns = Columns::from(args);
+
+        let tree = args.get_flag("tree");
+
+        let null = args.get_flag("null");
+
+        let quoting = Quoting::from(args);
+
+        // the unit options of `browse` belong to the subcommand
+        let unit =
+            Unit::from(args.subcommand_matches("browse").unwrap_or(args));
+
+        let export_ncdu = args.get_one::<PathBuf>("export-ncdu").cloned();
+
+        let save_report = args.get_one::<PathBuf>("save-report").cloned();
+
+        let browse = args.subcommand_matches("browse").map(Browse::from);
+
         Ok(Self {
             filter,
             count_links,
+            dedupe_across_dirs,
             max_depth,
+            all,
+            separate_dirs,
+            empty_dirs,
+            sort,
+            reverse,
+            limit,
+            threshold,
+            total,
+            link_memory,
+            threads,
+            on_parse_error,
+            max_parse_errors,
             mm_nodes,
             mm_local_work_dir,
             mm_global_work_dir,
             byte_mode,
             count_mode,
+            time_mode,
+            types,
+            allocation,
+            percent,
+            format,
+            columns,
+            tree,
+            null,
+            quoting,
+            unit,
+            export_ncdu,
+            save_report,
+            browse,
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum Filter {
     Group(gid_t),
     User(uid_t),
@@ -152,6 +286,22 @@ pub enum ByteMode {
 }
 
 impl ByteMode {
+    /// Returns a size of this policy attribute in bytes.
+    pub const fn bytes(self, size: u64) -> u64 {
+        match self {
+            Self::FileSize => size,
+            Self::KBAllocated => size.saturating_mul(1024),
+        }
+    }
+
+    /// Returns the other policy attribute, see `--allocation`.
+    pub const fn other(self) -> Self {
+        match self {
+            Self::FileSize => Self::KBAllocated,
+            Self::KBAllocated => Self::FileSize,
+        }
+    }
+
     pub const fn policy_attribute(self) -> &'static str {
         match self {
             Self::FileSize => "FILE_SIZE",
@@ -160,6 +310,240 @@ impl ByteMode {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeMode {
+    Modification,
+    Access,
+    Change,
+    Creation,
+}
+
+impl TimeMode {
+    pub const fn policy_attribute(self) -> &'static str {
+        match self {
+            Self::Modification => "MODIFICATION_TIME",
+            Self::Access => "ACCESS_TIME",
+            Self::Change => "CHANGE_TIME",
+            Self::Creation => "CREATION_TIME",
+        }
+    }
+}
+
+impl From<&str> for TimeMode {
+    fn from(time: &str) -> Self {
+        match time {
+            "mtime" => Self::Modification,
+            "atime" => Self::Access,
+            "ctime" => Self::Change,
+            "crtime" => Self::Creation,
+            _ => unreachable!("clap SHOULD only allow possible values"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Human,
+    Json,
+    JsonLines,
+    Csv,
+    Tsv,
+}
+
+/// Optional columns of `--format csv` and `--format tsv`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Columns {
+    pub owner: bool,
+    pub filter: bool,
+    pub depth: bool,
+}
+
+impl From<&ArgMatches> for Columns {
+    fn from(args: &ArgMatches) -> Self {
+        let mut columns = Self::default();
+
+        for column in args.get_many::<String>("columns").into_iter().flatten()
+        {
+            match column.as_str() {
+                "owner" => columns.owner = true,
+                "filter" => columns.filter = true,
+                "depth" => columns.depth = true,
+                _ => unreachable!("clap SHOULD only allow possible values"),
+            }
+        }
+
+        columns
+    }
+}
+
+impl From<&str> for Format {
+    fn from(format: &str) -> Self {
+        match format {
+            "human" => Self::Human,
+            "json" => Self::Json,
+            "jsonl" => Self::JsonLines,
+            "csv" => Self::Csv,
+            "tsv" => Self::Tsv,
+            _ => unreachable!("clap SHOULD only allow possible values"),
+        }
+    }
+}
+
+/// How paths are quoted in human output, see `--quoting-style`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quoting {
+    Literal,
+    Shell,
+    Escape,
+    C,
+}
+
+impl From<&ArgMatches> for Quoting {
+    fn from(args: &ArgMatches) -> Self {
+        let quoting = args
+            .get_one::<String>("quoting-style")
+            .expect("quoting style has a default value");
+
+        match quoting.as_str() {
+            "literal" => Self::Literal,
+            "shell" => Self::Shell,
+            "escape" => Self::Escape,
+            "c" => Self::C,
+            _ => unreachable!("clap SHOULD only allow possible values"),
+        }
+    }
+}
+
+/// The `browse` subcommand.
+#[derive(Clone, Debug)]
+pub struct Browse {
+    pub report: PathBuf,
+    pub sort: Sort,
+}
+
+impl From<&ArgMatches> for Browse {
+    fn from(args: &ArgMatches) -> Self {
+        let report = args
+            .get_one::<PathBuf>("report")
+            .cloned()
+            .expect("report is a required argument");
+
+        let sort = args
+            .get_one::<String>("sort")
+            .map(|sort| Sort::from(sort.as_str()))
+            .expect("sort has a default value");
+
+        Self { report, sort }
+    }
+}
+
+/// The order of entries, see `--sort`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sort {
+    Bytes,
+    Inodes,
+    Name,
+    Time,
+}
+
+impl From<&str> for Sort {
+    fn from(sort: &str) -> Self {
+        match sort {
+            "size" => Self::Bytes,
+            "name" => Self::Name,
+            "inodes" => Self::Inodes,
+            "mtime" => Self::Time,
+            _ => unreachable!("clap SHOULD only allow possible values"),
+        }
+    }
+}
+
+/// The unit of sizes in human output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Unit {
+    /// Human readable in powers of 1024.
+    Iec,
+
+    /// Human readable in powers of 1000.
+    Si,
+
+    /// Number of blocks of this size, rounded up, with an optional suffix.
+    Blocks(u64, Option<String>),
+}
+
+impl From<&ArgMatches> for Unit {
+    fn from(args: &ArgMatches) -> Self {
+        // all of these override each other, so at most one is present
+        if args.get_flag("si") {
+            Self::Si
+        } else if args.get_flag("bytes") {
+            Self::Blocks(1, None)
+        } else if args.get_flag("kilobytes") {
+            Self::Blocks(1 << 10, None)
+        } else if args.get_flag("megabytes") {
+            Self::Blocks(1 << 20, None)
+        } else if let Some((size, suffix)) =
+            args.get_one::<(u64, Option<String>)>("block-size")
+        {
+            Self::Blocks(*size, suffix.clone())
+        } else {
+            Self::Iec
+        }
+    }
+}
+
+/// What to do with invalid lines in the policy report.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnParseError {
+    /// Skip the DIR.
+    Fail,
+
+    /// Skip the line, the result is approximate.
+    Skip,
+
+    /// Like `Skip`, but print a warning for every skipped line.
+    Warn,
+}
+
+impl From<&str> for OnParseError {
+    fn from(mode: &str) -> Self {
+        match mode {
+            "fail" => Self::Fail,
+            "skip" => Self::Skip,
+            "warn" => Self::Warn,
+            _ => unreachable!("clap SHOULD only allow possible values"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Threshold {
+    /// Include only entries of at least this size.
+    Min(u64),
+
+    /// Include only entries of at most this size.
+    Max(u64),
+}
+
+impl Threshold {
+    /// Returns threshold from its command-line representation, where negative
+    /// values mean *at most*. A threshold of zero includes everything.
+    pub const fn new(threshold: i64) -> Option<Self> {
+        match threshold {
+            0 => None,
+            1.. => Some(Self::Min(threshold.unsigned_abs())),
+            _ => Some(Self::Max(threshold.unsigned_abs())),
+        }
+    }
+
+    pub const fn includes(self, size: u64) -> bool {
+        match self {
+            Self::Min(min) => size >= min,
+            Self::Max(max) => size <= max,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CountMode {
     Inodes,
@@ -186,6 +570,18 @@ impl From<&ArgMatches> for CountMode {
 // tests
 // ----------------------------------------------------------------------------
 
+#[cfg(test)]
+impl Config {
+    /// Returns config from command-line arguments, without the binary name.
+    pub fn from_args(args: &[&str]) -> Self {
+        let args = crate::cli::build().get_matches_from(
+            std::iter::once(clap::crate_name!()).chain(args.iter().copied()),
+        );
+
+        Self::try_from(&args).unwrap()
+    }
+}
+
 #[cfg(test)]
 impl From<ArgMatches> for CountMode {
     fn from(args: ArgMatches) -> Self {
@@ -195,7 +591,22 @@ impl From<ArgMatches> for CountMode {
 
 #[cfg(test)]
 mod test {
-    use super::CountMode;
+    use super::{CountMode, Threshold};
+
+    #[test]
+    fn threshold() {
+        assert_eq!(None, Threshold::new(0));
+        assert_eq!(Some(Threshold::Min(1024)), Threshold::new(1024));
+        assert_eq!(Some(Threshold::Max(1024)), Threshold::new(-1024));
+
+        assert!(Threshold::Min(1024).includes(1024));
+        assert!(Threshold::Min(1024).includes(1025));
+        assert!(!Threshold::Min(1024).includes(1023));
+
+        assert!(Threshold::Max(1024).includes(1024));
+        assert!(Threshold::Max(1024).includes(1023));
+        assert!(!Threshold::Max(1024).includes(1025));
+    }
 
     #[test]
     fn count_mode() {
@@ -28,18 +28,22 @@
 // ALLOW only windows deps ATM, we don't use these targets
 #![allow(clippy::multiple_crate_versions)]
 
+mod browse;
 mod cli;
 mod config;
 mod output;
 mod policy;
+mod time;
 mod usage;
 
 use std::io::{self, IsTerminal};
 use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 
 use crate::config::Config;
+use crate::output::{Output, Record};
+use crate::usage::GrandTotal;
 
 fn main() -> Result<()> {
     #[cfg(feature = "log")]
@@ -52,11 +56,27 @@ fn main() -> Result<()> {
     #[cfg(feature = "log")]
     log::debug!("{config:#?}");
 
+    if let Some(browse) = &config.browse {
+        return browse::run(browse, &config);
+    }
+
+    let mut output = Output::new(&config);
+
+    // also keeps track of hard links for --dedupe-across-dirs
+    let mut total = (config.total || config.dedupe_across_dirs)
+        .then(|| GrandTotal::new(&config));
+
+    // only the first DIR that succeeds is exported, see --export-ncdu and
+    // --save-report
+    let mut first = true;
+
     // ALLOW if let is easier to comprehend
     #[allow(clippy::option_if_let_else)]
     if let Some(dirs) = args.get_many::<PathBuf>("dir") {
         for dir in dirs {
-            run(dir, &config);
+            if run(dir, &config, total.as_mut(), first, &mut output) {
+                first = false;
+            }
         }
     } else {
         let interactive = std::io::stdin().is_terminal();
@@ -73,18 +93,55 @@ fn main() -> Result<()> {
         let lines = io::stdin().lines();
         for line in lines {
             let dir = line.unwrap();
-            run(Path::new(&dir), &config);
+            let dir = Path::new(&dir);
+
+            if run(dir, &config, total.as_mut(), first, &mut output) {
+                first = false;
+            }
         }
     }
 
+    if let Some(total) = total.filter(|_| config.total) {
+        let acc = total.finish().context("counting grand total")?;
+
+        output.record(&Record {
+            path: Path::new("total"),
+            acc: &acc,
+            dir: None,
+            scan: None,
+            parent: None,
+            top: None,
+        });
+    }
+
+    output.finish();
+
     Ok(())
 }
 
-fn run(dir: &Path, config: &Config) {
+/// Returns whether `dir` succeeded.
+fn run(
+    dir: &Path,
+    config: &Config,
+    mut total: Option<&mut GrandTotal>,
+    first: bool,
+    output: &mut Output,
+) -> bool {
     #[cfg(feature = "log")]
     log::debug!("running with directory {} ...", dir.display());
 
-    if let Err(error) = usage::run(dir, config) {
+    let mut result =
+        usage::run(dir, config, total.as_deref_mut(), first, output);
+
+    if let Some(total) = total {
+        if result.is_ok() {
+            result = total.commit().context("adding to grand total");
+        } else {
+            total.discard();
+        }
+    }
+
+    if let Err(error) = &result {
         let dir = dir.display();
 
         #[cfg(not(feature = "log"))]
@@ -93,4 +150,6 @@ fn run(dir: &Path, config: &Config) {
         #[cfg(feature = "log")]
         log::warn!("skipping directory {dir}: {error:#}");
     }
+
+    result.is_ok()
 }
@@ -23,27 +23,1312 @@
  *                                                                           *
  * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * */
 
-use std::path::Path;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 
-use bytesize::{ByteSize, Display};
+use bstr::ByteSlice;
+use bytesize::ByteSize;
+use libc::uid_t;
+use serde::Serialize;
 
-use crate::config::{ByteMode, Config, CountMode};
+use crate::config::{
+    ByteMode, Config, CountMode, Filter, Format, Quoting, Unit,
+};
+use crate::time::Timestamp;
+use crate::usage::{Acc, Scan, Types};
 
-pub fn output(dir: &Path, inodes: u64, bytes: u64, config: &Config) {
-    let humanized = match config.byte_mode {
-        ByteMode::FileSize => humanize(ByteSize::b(bytes)),
-        ByteMode::KBAllocated => humanize(ByteSize::kib(bytes)),
+/// A single line of output.
+pub struct Record<'a> {
+    pub path: &'a Path,
+    pub acc: &'a Acc,
+
+    /// The DIR this belongs to, `None` for the grand total.
+    pub dir: Option<&'a Path>,
+
+    /// The scan of the DIR, `None` for the grand total.
+    pub scan: Option<&'a Scan>,
+
+    /// The sums of the parent directory, `None` for the DIR itself and the
+    /// grand total, see `--percent`.
+    pub parent: Option<&'a Acc>,
+
+    /// The sums of the DIR, `None` for the grand total.
+    pub top: Option<&'a Acc>,
+}
+
+/// Width of the percentage bars.
+const BAR: u64 = 10;
+
+/// Writes records in the format of `--format`.
+pub struct Output<'a> {
+    config: &'a Config,
+    records: usize,
+
+    /// The DIR and records of the current tree, only with `--tree`.
+    tree: Option<(PathBuf, Vec<TreeRecord>)>,
+
+    /// Names of owners, only with `--columns owner`.
+    users: Users,
+}
+
+impl<'a> Output<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        Self {
+            config,
+            records: 0,
+            tree: None,
+            users: Users::default(),
+        }
+    }
+
+    pub fn record(&mut self, record: &Record) {
+        match self.config.format {
+            Format::Human if self.config.tree => self.tree_record(record),
+            Format::Human => {
+                let path = quote(record.path, self.config.quoting);
+                let line = human(record, &path, self.config);
+                self.line(&mut io::stdout().lock(), &line);
+            }
+            Format::Json => {
+                // the array is closed by finish, with the last newline
+                let separator = if self.records == 0 { "[" } else { "," };
+                print!("{separator}\n  {}", json(record, self.config));
+            }
+            Format::JsonLines => println!("{}", json(record, self.config)),
+            Format::Csv | Format::Tsv => {
+                if self.records == 0 {
+                    self.header();
+                }
+
+                let row = table(record, self.config, &mut self.users);
+                self.row(&row);
+            }
+        }
+
+        self.records += 1;
+    }
+
+    /// Terminates the output, must be called after all records.
+    pub fn finish(mut self) {
+        self.flush_tree();
+
+        match self.config.format {
+            Format::Json if self.records == 0 => println!("[]"),
+            Format::Json => println!("\n]"),
+            Format::Csv | Format::Tsv if self.records == 0 => self.header(),
+            _ => {}
+        }
+    }
+
+    /// Collects the records of a DIR, the tree is printed once all of them
+    /// are known.
+    fn tree_record(&mut self, record: &Record) {
+        let Some(dir) = record.dir else {
+            // the grand total is no part of any tree
+            self.flush_tree();
+            let path = quote(record.path, self.config.quoting);
+            let line = human(record, &path, self.config);
+            self.line(&mut io::stdout().lock(), &line);
+            return;
+        };
+
+        if self
+            .tree
+            .as_ref()
+            .is_some_and(|(current, _)| current != dir)
+        {
+            self.flush_tree();
+        }
+
+        let (_, records) = self
+            .tree
+            .get_or_insert_with(|| (dir.to_owned(), Vec::new()));
+
+        records.push(TreeRecord::from(record));
+    }
+
+    fn flush_tree(&mut self) {
+        if let Some((_, records)) = self.tree.take() {
+            let mut stdout = io::stdout().lock();
+
+            for line in tree(&records, self.config) {
+                self.line(&mut stdout, &line);
+            }
+        }
+    }
+
+    /// Writes a line of human output, terminated according to `--null`.
+    fn line(&self, out: &mut impl Write, line: &[u8]) {
+        let terminator = if self.config.null { b'\0' } else { b'\n' };
+
+        out.write_all(line)
+            .and_then(|()| out.write_all(&[terminator]))
+            .expect("failed printing to stdout");
+    }
+
+    fn header(&self) {
+        let header = header(self.config);
+        self.row(&header);
+    }
+
+    fn row<S: AsRef<str>>(&self, fields: &[S]) {
+        let tsv = self.config.format == Format::Tsv;
+
+        let separator = if tsv { "\t" } else { "," };
+        let escape: fn(&str) -> Cow<'_, str> =
+            if tsv { escape_tsv } else { escape_csv };
+
+        let mut fields = fields.iter().map(|field| escape(field.as_ref()));
+
+        let mut row = fields.next().map(Cow::into_owned).unwrap_or_default();
+
+        for field in fields {
+            row.push_str(separator);
+            row.push_str(&field);
+        }
+
+        println!("{row}");
+    }
+}
+
+// ----------------------------------------------------------------------------
+// human
+// ----------------------------------------------------------------------------
+
+/// Returns a line of human output, the columns followed by `dir`.
+fn human(record: &Record, dir: &[u8], config: &Config) -> Vec<u8> {
+    let Acc {
+        inodes,
+        bytes,
+        time,
+        types,
+        sizes,
+        ..
+    } = *record.acc;
+
+    let humanized = format_size(config.byte_mode.bytes(bytes), &config.unit);
+
+    let mut columns = match config.count_mode {
+        CountMode::Both => vec![humanized, inodes.to_string()],
+        CountMode::Bytes => vec![humanized],
+        CountMode::Inodes => vec![inodes.to_string()],
+    };
+
+    if config.allocation {
+        let apparent = format_size(sizes.apparent, &config.unit);
+        let allocated = ByteMode::KBAllocated.bytes(sizes.allocated);
+        let allocated = format_size(allocated, &config.unit);
+
+        let ratio = sizes
+            .ratio()
+            .map_or_else(|| String::from("-"), |ratio| format!("{ratio:.2}"));
+
+        columns.extend([apparent, allocated, ratio]);
+    }
+
+    if config.types {
+        let Types {
+            files,
+            dirs,
+            symlinks,
+            other,
+        } = types;
+
+        columns.extend([files, dirs, symlinks, other].map(|n| n.to_string()));
+    }
+
+    if config.time_mode.is_some() {
+        columns.push(
+            time.map_or_else(|| String::from("-"), |time| time.to_string()),
+        );
+    }
+
+    if config.percent {
+        let percent = |whole: Option<&Acc>, amount: fn(&Acc) -> u64| {
+            whole.map_or_else(
+                || String::from("-"),
+                |whole| percent(amount(record.acc), amount(whole)),
+            )
+        };
+
+        columns.extend([
+            percent(record.parent, |acc| acc.bytes),
+            percent(record.top, |acc| acc.bytes),
+            percent(record.parent, |acc| acc.inodes),
+            percent(record.top, |acc| acc.inodes),
+        ]);
+    }
+
+    // the path is written as is, it is not necessarily valid UTF-8
+    let mut line = columns.join("\t").into_bytes();
+    line.push(b'\t');
+    line.extend_from_slice(dir);
+
+    line
+}
+
+/// Returns the lines of `--tree` output for the records of a DIR.
+///
+/// The parent of each record is its nearest ancestor among the records, which
+/// is not necessarily its parent directory, e.g. with a negative threshold.
+/// Siblings keep the order of the records, see `--sort`.
+fn tree(records: &[TreeRecord], config: &Config) -> Vec<Vec<u8>> {
+    let mut order = (0..records.len()).collect::<Vec<_>>();
+    order.sort_by(|a, b| records[*a].path.cmp(&records[*b].path));
+
+    let mut roots = vec![];
+    let mut children = vec![vec![]; records.len()];
+    let mut parents = vec![None; records.len()];
+
+    // in path order, ancestors are visited before descendants
+    let mut ancestors: Vec<usize> = vec![];
+
+    for index in order {
+        let path = &records[index].path;
+
+        while let Some(&ancestor) = ancestors.last() {
+            if path.starts_with(&records[ancestor].path) {
+                break;
+            }
+
+            ancestors.pop();
+        }
+
+        if let Some(&parent) = ancestors.last() {
+            children[parent].push(index);
+            parents[index] = Some(parent);
+        } else {
+            roots.push(index);
+        }
+
+        ancestors.push(index);
+    }
+
+    roots.sort_unstable();
+
+    for siblings in &mut children {
+        siblings.sort_unstable();
+    }
+
+    let amount = |acc: &Acc| match config.count_mode {
+        CountMode::Inodes => acc.inodes,
+        CountMode::Bytes | CountMode::Both => acc.bytes,
+    };
+
+    let mut lines = vec![];
+
+    // depth first with the prefix of the children and whether this is the
+    // last child of its parent
+    let mut stack: Vec<(usize, String, bool)> = roots
+        .into_iter()
+        .rev()
+        .map(|root| (root, String::new(), true))
+        .collect();
+
+    while let Some((index, prefix, last)) = stack.pop() {
+        let record = &records[index];
+        let path = &record.path;
+
+        let (name, connector, whole) = parents[index].map_or_else(
+            || (path.as_path(), "", amount(&record.acc)),
+            |parent| {
+                let parent = &records[parent];
+                let name = path.strip_prefix(&parent.path).unwrap_or(path);
+                let connector = if last { "└── " } else { "├── " };
+                (name, connector, amount(&parent.acc))
+            },
+        );
+
+        let part = amount(&record.acc);
+
+        let mut path = format!(
+            "{:>6} [{}] {prefix}{connector}",
+            percent(part, whole),
+            bar(part, whole),
+        )
+        .into_bytes();
+
+        path.extend_from_slice(&quote(name, config.quoting));
+
+        lines.push(human(&record.as_record(), &path, config));
+
+        let prefix = match (parents[index], last) {
+            (None, _) => prefix,
+            (Some(_), true) => prefix + "    ",
+            (Some(_), false) => prefix + "│   ",
+        };
+
+        let count = children[index].len();
+
+        for (i, &child) in children[index].iter().enumerate().rev() {
+            stack.push((child, prefix.clone(), i + 1 == count));
+        }
+    }
+
+    lines
+}
+
+/// A record kept until the tree of its DIR is complete.
+struct TreeRecord {
+    path: PathBuf,
+    acc: Acc,
+    parent: Option<Acc>,
+    top: Option<Acc>,
+}
+
+impl TreeRecord {
+    fn as_record(&self) -> Record<'_> {
+        Record {
+            path: self.path.as_path(),
+            acc: &self.acc,
+            dir: None,
+            scan: None,
+            parent: self.parent.as_ref(),
+            top: self.top.as_ref(),
+        }
+    }
+}
+
+impl From<&Record<'_>> for TreeRecord {
+    fn from(record: &Record) -> Self {
+        Self {
+            path: record.path.to_owned(),
+            acc: *record.acc,
+            parent: record.parent.copied(),
+            top: record.top.copied(),
+        }
+    }
+}
+
+/// Returns the bytes of `path` quoted according to `--quoting-style`.
+fn quote(path: &Path, quoting: Quoting) -> Cow<'_, [u8]> {
+    let bytes = path.as_os_str().as_bytes();
+
+    match quoting {
+        Quoting::Literal => Cow::Borrowed(bytes),
+        Quoting::Shell => quote_shell(bytes),
+        Quoting::Escape => Cow::Owned(escape(bytes, Quoting::Escape)),
+        Quoting::C => {
+            let mut quoted = vec![b'"'];
+            quoted.extend(escape(bytes, Quoting::C));
+            quoted.push(b'"');
+            Cow::Owned(quoted)
+        }
+    }
+}
+
+/// Quotes with single quotes unless only safe characters are contained.
+fn quote_shell(bytes: &[u8]) -> Cow<'_, [u8]> {
+    let safe = |byte: &u8| {
+        byte.is_ascii_alphanumeric() || b"%+,-./:=@_".contains(byte)
     };
 
-    let dir = dir.display();
+    if !bytes.is_empty() && bytes.iter().all(safe) {
+        return Cow::Borrowed(bytes);
+    }
+
+    let mut quoted = vec![b'\''];
+
+    for &byte in bytes {
+        if byte == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(byte);
+        }
+    }
+
+    quoted.push(b'\'');
+
+    Cow::Owned(quoted)
+}
+
+/// Escapes backslashes, control characters and invalid UTF-8 like in C
+/// string literals, and either spaces or double quotes.
+fn escape(bytes: &[u8], quoting: Quoting) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(bytes.len());
+
+    for (start, end, c) in bytes.char_indices() {
+        let raw = &bytes[start..end];
+
+        // invalid UTF-8 is decoded as the replacement character
+        let invalid =
+            c == char::REPLACEMENT_CHARACTER && raw != "\u{FFFD}".as_bytes();
+
+        match c {
+            '\\' => escaped.extend_from_slice(b"\\\\"),
+            '\n' => escaped.extend_from_slice(b"\\n"),
+            '\t' => escaped.extend_from_slice(b"\\t"),
+            '\r' => escaped.extend_from_slice(b"\\r"),
+            ' ' if quoting == Quoting::Escape => {
+                escaped.extend_from_slice(b"\\ ");
+            }
+            '"' if quoting == Quoting::C => escaped.extend_from_slice(b"\\\""),
+            _ if invalid || c.is_control() => {
+                for byte in raw {
+                    escaped.extend(format!("\\{byte:03o}").bytes());
+                }
+            }
+            _ => escaped.extend_from_slice(raw),
+        }
+    }
+
+    escaped
+}
+
+/// Returns `part` of `whole` in percent with one decimal.
+pub fn percent(part: u64, whole: u64) -> String {
+    let permille = (u128::from(part) * 1000)
+        .checked_div(u128::from(whole))
+        .unwrap_or(0);
+
+    format!("{}.{}%", permille / 10, permille % 10)
+}
+
+/// Returns a bar filled in proportion of `part` to `whole`.
+pub fn bar(part: u64, whole: u64) -> String {
+    let filled = (u128::from(part) * u128::from(BAR))
+        .checked_div(u128::from(whole))
+        .unwrap_or(0)
+        .min(u128::from(BAR));
+
+    (0..BAR)
+        .map(|i| if u128::from(i) < filled { '#' } else { ' ' })
+        .collect()
+}
+
+/// Formats a size in bytes in the unit of the unit options.
+pub fn format_size(bytes: u64, unit: &Unit) -> String {
+    match unit {
+        Unit::Iec => ByteSize::b(bytes).display().iec_short().to_string(),
+        Unit::Si => ByteSize::b(bytes).display().si_short().to_string(),
+        Unit::Blocks(size, suffix) => {
+            let blocks = bytes.div_ceil(*size);
+            let suffix = suffix.as_deref().unwrap_or_default();
+            format!("{blocks}{suffix}")
+        }
+    }
+}
+
+/// Returns the depth of the path within its DIR.
+fn depth(record: &Record) -> Option<usize> {
+    record.dir.map(|dir| {
+        let depth = record.path.components().count();
+        depth.saturating_sub(dir.components().count())
+    })
+}
+
+// ----------------------------------------------------------------------------
+// csv and tsv
+// ----------------------------------------------------------------------------
+
+fn header(config: &Config) -> Vec<&'static str> {
+    let mut header = vec!["path", "bytes", "inodes"];
+
+    if config.time_mode.is_some() {
+        header.push("time");
+    }
+
+    if config.types {
+        header.extend(["files", "dirs", "symlinks", "other"]);
+    }
+
+    if config.allocation {
+        header.extend(["apparent", "allocated", "ratio"]);
+    }
+
+    if config.percent {
+        header.extend([
+            "bytes_of_parent",
+            "bytes_of_dir",
+            "inodes_of_parent",
+            "inodes_of_dir",
+        ]);
+    }
+
+    header.push("skipped_lines");
+
+    if config.columns.owner {
+        header.push("owner");
+    }
+
+    if config.columns.filter {
+        header.push("filter");
+    }
+
+    if config.columns.depth {
+        header.push("depth");
+    }
+
+    header
+}
+
+/// Returns the fields of a row, with raw numbers, matching `header`.
+fn table(record: &Record, config: &Config, users: &mut Users) -> Vec<String> {
+    let acc = record.acc;
+
+    let bytes = config.byte_mode.bytes(acc.bytes);
+
+    let mut row = vec![
+        record.path.to_string_lossy().into_owned(),
+        bytes.to_string(),
+        acc.inodes.to_string(),
+    ];
+
+    if config.time_mode.is_some() {
+        row.push(acc.time.map(Timestamp::iso8601).unwrap_or_default());
+    }
+
+    if config.types {
+        let types = acc.types;
+        row.extend(
+            [types.files, types.dirs, types.symlinks, types.other]
+                .map(|count| count.to_string()),
+        );
+    }
+
+    if config.allocation {
+        let sizes = acc.sizes;
+        row.push(sizes.apparent.to_string());
+        row.push(ByteMode::KBAllocated.bytes(sizes.allocated).to_string());
+        row.push(
+            sizes
+                .ratio()
+                .map(|ratio| format!("{ratio:.4}"))
+                .unwrap_or_default(),
+        );
+    }
+
+    if config.percent {
+        let shares = shares(record);
+        row.extend(
+            [
+                shares.parent.bytes,
+                shares.dir.bytes,
+                shares.parent.inodes,
+                shares.dir.inodes,
+            ]
+            .map(|share| {
+                share.map(|share| format!("{share:.2}")).unwrap_or_default()
+            }),
+        );
+    }
+
+    row.push(acc.skipped.to_string());
+
+    if config.columns.owner {
+        row.push(
+            acc.owner
+                .map(|uid| users.name(uid).to_owned())
+                .unwrap_or_default(),
+        );
+    }
+
+    if config.columns.filter {
+        row.push(match config.filter {
+            Filter::Group(group) => format!("group:{group}"),
+            Filter::User(user) => format!("user:{user}"),
+            Filter::None => String::new(),
+        });
+    }
+
+    if config.columns.depth {
+        row.push(
+            depth(record)
+                .map(|depth| depth.to_string())
+                .unwrap_or_default(),
+        );
+    }
+
+    row
+}
+
+/// Names of user IDs, each of them is looked up only once.
+#[derive(Default)]
+struct Users(HashMap<uid_t, String>);
+
+impl Users {
+    /// Returns the name of the user `uid`, or the user ID if it has none.
+    fn name(&mut self, uid: uid_t) -> &str {
+        self.0
+            .entry(uid)
+            .or_insert_with(|| match pwd_grp::getpwuid(uid) {
+                Ok(Some(passwd)) => passwd.name,
+                _ => uid.to_string(),
+            })
+    }
+}
+
+/// Quotes a field if necessary, see RFC 4180.
+fn escape_csv(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Escapes tabs, line breaks and backslashes, which TSV can't contain.
+fn escape_tsv(field: &str) -> Cow<'_, str> {
+    if field.contains(['\t', '\n', '\r', '\\']) {
+        let field = field
+            .replace('\\', "\\\\")
+            .replace('\t', "\\t")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r");
+
+        Cow::Owned(field)
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+// ----------------------------------------------------------------------------
+// json
+// ----------------------------------------------------------------------------
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    path: Cow<'a, str>,
+    dir: Option<Cow<'a, str>>,
+
+    /// Depth of `path` within `dir`.
+    depth: Option<usize>,
+
+    bytes: u64,
+    inodes: u64,
+    byte_mode: &'static str,
+    filter: Option<JsonFilter>,
+    max_depth: Option<usize>,
+
+    /// ISO 8601 without time zone, the policy engine reports local time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    types: Option<JsonTypes>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sizes: Option<JsonSizes>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    percent: Option<Shares>,
+
+    approximate: bool,
+    skipped: u64,
+    scan: Option<JsonScan>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum JsonFilter {
+    Group(u32),
+    User(u32),
+}
+
+#[derive(Serialize)]
+struct JsonTypes {
+    files: u64,
+    dirs: u64,
+    symlinks: u64,
+    other: u64,
+}
+
+#[derive(Serialize)]
+struct JsonSizes {
+    apparent: u64,
+    allocated: u64,
+    ratio: Option<f64>,
+}
+
+/// Shares of the parent directory and of the DIR in percent, see `--percent`.
+#[derive(Serialize)]
+struct Shares {
+    parent: Share,
+    dir: Share,
+}
+
+#[derive(Serialize)]
+struct Share {
+    bytes: Option<f64>,
+    inodes: Option<f64>,
+}
 
-    match config.count_mode {
-        CountMode::Both => println!("{humanized}\t{inodes}\t{dir}"),
-        CountMode::Bytes => println!("{humanized}\t{dir}"),
-        CountMode::Inodes => println!("{inodes}\t{dir}"),
+impl Share {
+    fn new(acc: &Acc, whole: Option<&Acc>) -> Self {
+        Self {
+            bytes: whole.and_then(|whole| share(acc.bytes, whole.bytes)),
+            inodes: whole.and_then(|whole| share(acc.inodes, whole.inodes)),
+        }
     }
 }
 
-fn humanize(bytes: ByteSize) -> Display {
-    bytes.display().iec().iec_short()
+fn shares(record: &Record) -> Shares {
+    Shares {
+        parent: Share::new(record.acc, record.parent),
+        dir: Share::new(record.acc, record.top),
+    }
+}
+
+/// Returns `part` of `whole` in percent, `None` if `whole` is zero.
+fn share(part: u64, whole: u64) -> Option<f64> {
+    // ALLOW precision loss is irrelevant for a percentage
+    #[allow(clippy::cast_precision_loss)]
+    let share = part as f64 * 100.0 / whole as f64;
+
+    (whole > 0).then_some(share)
+}
+
+#[derive(Serialize)]
+struct JsonScan {
+    /// Start of `mmapplypolicy` in seconds since the epoch.
+    started: u64,
+
+    /// Duration of `mmapplypolicy` in seconds.
+    duration: f64,
+}
+
+fn json(record: &Record, config: &Config) -> String {
+    let acc = record.acc;
+
+    let bytes = config.byte_mode.bytes(acc.bytes);
+
+    let filter = match config.filter {
+        Filter::Group(group) => Some(JsonFilter::Group(group)),
+        Filter::User(user) => Some(JsonFilter::User(user)),
+        Filter::None => None,
+    };
+
+    let depth = depth(record);
+
+    let types = config.types.then_some(JsonTypes {
+        files: acc.types.files,
+        dirs: acc.types.dirs,
+        symlinks: acc.types.symlinks,
+        other: acc.types.other,
+    });
+
+    let sizes = config.allocation.then(|| JsonSizes {
+        apparent: acc.sizes.apparent,
+        allocated: ByteMode::KBAllocated.bytes(acc.sizes.allocated),
+        ratio: acc.sizes.ratio(),
+    });
+
+    let scan = record.scan.map(|scan| JsonScan {
+        started: scan
+            .started
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |started| started.as_secs()),
+        duration: scan.duration.as_secs_f64(),
+    });
+
+    let record = JsonRecord {
+        path: record.path.to_string_lossy(),
+        dir: record.dir.map(Path::to_string_lossy),
+        depth,
+        bytes,
+        inodes: acc.inodes,
+        byte_mode: config.byte_mode.policy_attribute(),
+        filter,
+        max_depth: config.max_depth,
+        time: acc.time.map(Timestamp::iso8601),
+        types,
+        sizes,
+        percent: config.percent.then(|| shares(record)),
+        approximate: acc.skipped > 0,
+        skipped: acc.skipped,
+        scan,
+    };
+
+    serde_json::to_string(&record).expect("records are always serializable")
+}
+
+// ----------------------------------------------------------------------------
+// tests
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::time::Duration;
+
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    #[test]
+    fn json_record() {
+        let config =
+            Config::from_args(&["--format=jsonl", "-d2", "--kb-allocated"]);
+
+        let scan = Scan {
+            started: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            duration: Duration::from_millis(1500),
+        };
+
+        let record = Record {
+            path: Path::new("/data/test/a"),
+            acc: &Acc::from((3, 4)),
+            dir: Some(Path::new("/data/test")),
+            scan: Some(&scan),
+            parent: None,
+            top: None,
+        };
+
+        let record: Value =
+            serde_json::from_str(&json(&record, &config)).expect("valid JSON");
+
+        assert_eq!(
+            record,
+            json!({
+                "path": "/data/test/a",
+                "dir": "/data/test",
+                "depth": 1,
+                "bytes": 4096,
+                "inodes": 3,
+                "byte_mode": "KB_ALLOCATED",
+                "filter": null,
+                "max_depth": 2,
+                "approximate": false,
+                "skipped": 0,
+                "scan": {
+                    "started": 1_700_000_000,
+                    "duration": 1.5,
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn json_record_total() {
+        let config =
+            Config::from_args(&["--format=json", "--types", "--time"]);
+
+        let mut acc = Acc::from((2, 1024));
+        acc.skipped = 1;
+        acc.types.files = 2;
+        acc.time = Timestamp::parse("2024-03-01", "12:00:30");
+
+        let record = Record {
+            path: Path::new("total"),
+            acc: &acc,
+            dir: None,
+            scan: None,
+            parent: None,
+            top: None,
+        };
+
+        let record: Value =
+            serde_json::from_str(&json(&record, &config)).expect("valid JSON");
+
+        assert_eq!(record["path"], "total");
+        assert_eq!(record["dir"], Value::Null);
+        assert_eq!(record["depth"], Value::Null);
+        assert_eq!(record["scan"], Value::Null);
+        assert_eq!(record["approximate"], true);
+        assert_eq!(record["time"], "2024-03-01T12:00:30");
+        assert_eq!(record["types"]["files"], 2);
+        assert_eq!(record["types"]["dirs"], 0);
+        assert!(record.get("sizes").is_none());
+    }
+
+    #[test]
+    fn table_row() {
+        let config = Config::from_args(&[
+            "--format=csv",
+            "-d1",
+            "--columns",
+            "filter,depth",
+            "--kb-allocated",
+        ]);
+
+        let record = Record {
+            path: Path::new("/data/test/a"),
+            acc: &Acc::from((3, 4)),
+            dir: Some(Path::new("/data/test")),
+            scan: None,
+            parent: None,
+            top: None,
+        };
+
+        assert_eq!(
+            header(&config),
+            [
+                "path",
+                "bytes",
+                "inodes",
+                "skipped_lines",
+                "filter",
+                "depth"
+            ]
+        );
+        assert_eq!(
+            table(&record, &config, &mut Users::default()),
+            ["/data/test/a", "4096", "3", "0", "", "1"]
+        );
+    }
+
+    #[test]
+    fn table_optional_columns() {
+        let config =
+            Config::from_args(&["--format=tsv", "--types", "--allocation"]);
+
+        let mut acc = Acc::from((1, 1024));
+        acc.types.files = 1;
+        acc.sizes.apparent = 1024;
+        acc.sizes.allocated = 2;
+
+        let record = Record {
+            path: Path::new("total"),
+            acc: &acc,
+            dir: None,
+            scan: None,
+            parent: None,
+            top: None,
+        };
+
+        let header = header(&config);
+        let row = table(&record, &config, &mut Users::default());
+
+        assert_eq!(header.len(), row.len());
+        assert_eq!(
+            row,
+            [
+                "total", "1024", "1", "1", "0", "0", "0", "1024", "2048",
+                "2.0000", "0"
+            ]
+        );
+    }
+
+    #[test]
+    fn table_time_owner() {
+        let config =
+            Config::from_args(&["--format=csv", "--time", "--columns=owner"]);
+
+        let mut acc = Acc::from((1, 1024));
+        acc.time = Timestamp::parse("2024-03-01", "12:00:30");
+        acc.owner = Some(4_000_000);
+
+        let record = Record {
+            path: Path::new("/data/test"),
+            acc: &acc,
+            dir: Some(Path::new("/data/test")),
+            scan: None,
+            parent: None,
+            top: None,
+        };
+
+        // the name is looked up only once
+        let mut users = Users(HashMap::from([(4_000_000, "cached".into())]));
+
+        let header = header(&config);
+        let row = table(&record, &config, &mut users);
+
+        assert_eq!(
+            header,
+            ["path", "bytes", "inodes", "time", "skipped_lines", "owner"]
+        );
+        assert_eq!(
+            row,
+            [
+                "/data/test",
+                "1024",
+                "1",
+                "2024-03-01T12:00:30",
+                "0",
+                "cached"
+            ]
+        );
+
+        let mut users = Users::default();
+        assert_eq!("4000000", users.name(4_000_000));
+    }
+
+    #[test]
+    fn table_escape() {
+        assert_eq!(escape_csv("/data/plain"), "/data/plain");
+        assert_eq!(escape_csv("/data/a,b"), "\"/data/a,b\"");
+        assert_eq!(escape_csv("/data/\"q\""), "\"/data/\"\"q\"\"\"");
+        assert_eq!(escape_csv("/data/new\nline"), "\"/data/new\nline\"");
+
+        assert_eq!(escape_tsv("/data/a,b"), "/data/a,b");
+        assert_eq!(escape_tsv("/data/a\tb"), "/data/a\\tb");
+        assert_eq!(escape_tsv("/data/new\nline"), "/data/new\\nline");
+        assert_eq!(escape_tsv("/data/back\\slash"), "/data/back\\\\slash");
+    }
+
+    #[test]
+    fn units() {
+        let unit = |args: &[&str]| Config::from_args(args).unit;
+
+        assert_eq!(format_size(1536, &unit(&[])), "1.5K");
+        assert_eq!(format_size(1536, &unit(&["-h"])), "1.5K");
+        assert_eq!(format_size(1500, &unit(&["--si"])), "1.5k");
+        assert_eq!(format_size(1536, &unit(&["-b"])), "1536");
+        assert_eq!(format_size(1536, &unit(&["-k"])), "2");
+        assert_eq!(format_size(1 << 20, &unit(&["-m"])), "1");
+        assert_eq!(format_size((1 << 20) + 1, &unit(&["-m"])), "2");
+        assert_eq!(format_size(0, &unit(&["-m"])), "0");
+        assert_eq!(format_size(4096, &unit(&["-B", "2K"])), "2");
+        assert_eq!(format_size(3 << 20, &unit(&["-BM"])), "3M");
+
+        // the last one wins
+        assert_eq!(format_size(1536, &unit(&["-k", "-b"])), "1536");
+        assert_eq!(format_size(1536, &unit(&["-b", "-h"])), "1.5K");
+        assert_eq!(format_size(1536, &unit(&["-BM", "-k"])), "2");
+
+        // KB_ALLOCATED is converted to bytes first
+        let config = Config::from_args(&["--kb-allocated", "-b"]);
+        let bytes = config.byte_mode.bytes(3);
+        assert_eq!(format_size(bytes, &config.unit), "3072");
+    }
+
+    fn text(line: Vec<u8>) -> String {
+        String::from_utf8(line).expect("valid UTF-8")
+    }
+
+    fn tree_record(path: &str, acc: Acc) -> TreeRecord {
+        TreeRecord {
+            path: PathBuf::from(path),
+            acc,
+            parent: None,
+            top: None,
+        }
+    }
+
+    #[test]
+    fn skipped_lines() {
+        let acc = Acc {
+            skipped: 2,
+            ..Acc::from((1, 1024))
+        };
+
+        let record = Record {
+            path: Path::new("/data/test"),
+            acc: &acc,
+            dir: Some(Path::new("/data/test")),
+            scan: None,
+            parent: None,
+            top: None,
+        };
+
+        // the columns are the same, the note is printed to stderr
+        let config = Config::from_args(&["--on-parse-error=skip", "--both"]);
+        assert_eq!(
+            "1.0K\t1\t/data/test",
+            text(human(&record, b"/data/test", &config))
+        );
+
+        let row = table(&record, &config, &mut Users::default());
+        let column = header(&config)
+            .iter()
+            .position(|name| *name == "skipped_lines")
+            .unwrap();
+        assert_eq!("2", row[column]);
+    }
+
+    #[test]
+    fn percent_columns() {
+        let config = Config::from_args(&["-d2", "--percent", "--both"]);
+
+        let top = Acc::from((8, 8192));
+        let parent = Acc::from((4, 4096));
+
+        let record = Record {
+            path: Path::new("/data/test/a/b"),
+            acc: &Acc::from((1, 1024)),
+            dir: Some(Path::new("/data/test")),
+            scan: None,
+            parent: Some(&parent),
+            top: Some(&top),
+        };
+
+        assert_eq!(
+            "1.0K\t1\t25.0%\t12.5%\t25.0%\t12.5%\t/data/test/a/b",
+            text(human(&record, b"/data/test/a/b", &config))
+        );
+
+        // the DIR itself has no parent
+        let record = Record {
+            path: Path::new("/data/test"),
+            acc: &top,
+            parent: None,
+            ..record
+        };
+
+        assert_eq!(
+            "8.0K\t8\t-\t100.0%\t-\t100.0%\t/data/test",
+            text(human(&record, b"/data/test", &config))
+        );
+
+        let config = Config::from_args(&["-d2", "--percent", "--format=csv"]);
+
+        assert_eq!(
+            header(&config)[3..7],
+            [
+                "bytes_of_parent",
+                "bytes_of_dir",
+                "inodes_of_parent",
+                "inodes_of_dir"
+            ]
+        );
+        assert_eq!(
+            table(&record, &config, &mut Users::default())[3..7],
+            ["", "100.00", "", "100.00"]
+        );
+
+        let config = Config::from_args(&["-d2", "--percent", "--format=json"]);
+
+        let json: Value =
+            serde_json::from_str(&json(&record, &config)).expect("valid JSON");
+
+        assert_eq!(
+            json["percent"],
+            json!({
+                "parent": { "bytes": null, "inodes": null },
+                "dir": { "bytes": 100.0, "inodes": 100.0 },
+            })
+        );
+    }
+
+    #[test]
+    fn tree_lines() {
+        let config = Config::from_args(&["-d2", "--bytes"]);
+
+        // siblings keep the order of the records
+        let records = [
+            ("/data/test", 8192),
+            ("/data/test/b", 2048),
+            ("/data/test/a", 4096),
+            ("/data/test/a/c", 1024),
+            ("/data/test/a/d", 2048),
+            ("/data/test/b/e", 1024),
+        ]
+        .into_iter()
+        .map(|(path, bytes)| tree_record(path, Acc::from((1, bytes))))
+        .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                "8192\t100.0% [##########] /data/test",
+                "2048\t 25.0% [##        ] ├── b",
+                "1024\t 50.0% [#####     ] │   └── e",
+                "4096\t 50.0% [#####     ] └── a",
+                "1024\t 25.0% [##        ]     ├── c",
+                "2048\t 50.0% [#####     ]     └── d",
+            ],
+            tree(&records, &config)
+                .into_iter()
+                .map(text)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn tree_gaps() {
+        let config = Config::from_args(&["-d2", "--inodes"]);
+
+        // the parent of b/c is missing, e.g. with a negative threshold
+        let records = [("/data/test", 4), ("/data/test/b/c", 1)]
+            .into_iter()
+            .map(|(path, inodes)| tree_record(path, Acc::from((inodes, 0))))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                "4\t100.0% [##########] /data/test",
+                "1\t 25.0% [##        ] └── b/c",
+            ],
+            tree(&records, &config)
+                .into_iter()
+                .map(text)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn bars() {
+        assert_eq!("0.0%", percent(0, 0));
+        assert_eq!("50.0%", percent(1, 2));
+        assert_eq!("33.3%", percent(1, 3));
+        assert_eq!("100.0%", percent(3, 3));
+
+        assert_eq!("          ", bar(0, 0));
+        assert_eq!("#####     ", bar(1, 2));
+        assert_eq!("##########", bar(3, 3));
+    }
+
+    #[test]
+    fn quoting() {
+        let quoted = |path: &[u8], quoting| {
+            quote(Path::new(OsStr::from_bytes(path)), quoting).into_owned()
+        };
+
+        let plain = b"/data/test/a_b-1.txt";
+        let special = b"/data/it's a\tb\n\"\\\xff\xc3\xa4";
+
+        for quoting in [
+            Quoting::Literal,
+            Quoting::Shell,
+            Quoting::Escape,
+            Quoting::C,
+        ] {
+            let expected = match quoting {
+                Quoting::C => [b"\"", &plain[..], b"\""].concat(),
+                _ => plain.to_vec(),
+            };
+
+            assert_eq!(expected, quoted(plain, quoting), "{quoting:?}");
+        }
+
+        // the raw bytes are preserved, including invalid UTF-8
+        assert_eq!(special.to_vec(), quoted(special, Quoting::Literal));
+
+        assert_eq!(
+            b"'/data/it'\\''s a\tb\n\"\\\xff\xc3\xa4'".to_vec(),
+            quoted(special, Quoting::Shell)
+        );
+        assert_eq!(
+            [&br#"/data/it's\ a\tb\n"\\\377"#[..], "ä".as_bytes()].concat(),
+            quoted(special, Quoting::Escape)
+        );
+        assert_eq!(
+            [&br#""/data/it's a\tb\n\"\\\377"#[..], "ä\"".as_bytes()].concat(),
+            quoted(special, Quoting::C)
+        );
+        assert_eq!(b"''".to_vec(), quoted(b"", Quoting::Shell));
+    }
+
+    #[test]
+    fn null_terminated() {
+        let config = Config::from_args(&["-0", "--quoting-style=literal"]);
+        assert!(config.null);
+
+        let output = Output::new(&config);
+        let mut out = Vec::new();
+
+        let acc = Acc::from((1, 1024));
+
+        for path in [&b"/data/new\nline\xff"[..], b"total"] {
+            let record = Record {
+                path: Path::new(OsStr::from_bytes(path)),
+                acc: &acc,
+                dir: None,
+                scan: None,
+                parent: None,
+                top: None,
+            };
+
+            let path = quote(record.path, config.quoting);
+            output.line(&mut out, &human(&record, &path, &config));
+        }
+
+        assert_eq!(
+            b"1.0K\t/data/new\nline\xff\x001.0K\ttotal\0".to_vec(),
+            out
+        );
+    }
 }
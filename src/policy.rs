@@ -23,25 +23,55 @@
  *                                                                           *
  * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * */
 
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write as _;
 use std::fs::File;
 use std::io::{self, Write};
-use std::path::Path;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Result, anyhow};
 use bstr::ByteSlice;
 use libc::{gid_t, uid_t};
 
 use crate::config::{Config, Filter};
+use crate::time::Timestamp;
+
+/// Percent-encodes all special characters in path names, except these.
+///
+/// A newline in a file name would otherwise split a line of the report. Space
+/// and colon must not be encoded because they are part of the SHOW columns.
+const ESCAPE: &str = "%/: ";
 
 pub fn size(file: &Path, config: &Config) -> io::Result<()> {
     let mut file = File::create(file)?;
 
     let attribute = config.byte_mode.policy_attribute();
+    let layout = Layout::from(config);
+
+    let mut show = format!(
+        "VARCHAR({attribute}) || ' ' || VARCHAR(NLINK) || ' ' || MODE"
+    );
+
+    if layout.other {
+        let attribute = config.byte_mode.other().policy_attribute();
+        write!(show, " || ' ' || VARCHAR({attribute})").unwrap();
+    }
+
+    if let Some(time_mode) = config.time_mode {
+        let attribute = time_mode.policy_attribute();
+        write!(show, " || ' ' || VARCHAR({attribute})").unwrap();
+    }
+
+    if layout.owner {
+        show.push_str(" || ' ' || VARCHAR(USER_ID)");
+    }
 
     let content = match &config.filter {
-        Filter::Group(group) => policy_group(*group, attribute),
-        Filter::User(user) => policy_user(*user, attribute),
-        Filter::None => policy_default(attribute),
+        Filter::Group(group) => policy_group(*group, &show),
+        Filter::User(user) => policy_user(*user, &show),
+        Filter::None => policy_default(&show),
     };
 
     file.write_all(content.as_bytes())?;
@@ -49,98 +79,323 @@ pub fn size(file: &Path, config: &Config) -> io::Result<()> {
     Ok(())
 }
 
-fn policy_group(group: gid_t, attribute: &str) -> String {
+fn policy_group(group: gid_t, show: &str) -> String {
     format!(
         "RULE
   EXTERNAL LIST 'size'
   EXEC ''
+  ESCAPE '{ESCAPE}'
 
 RULE 'TOTAL'
   LIST 'size'
   DIRECTORIES_PLUS
-  SHOW(VARCHAR({attribute}) || ' ' || VARCHAR(NLINK))
+  SHOW({show})
   WHERE GROUP_ID = {group}
 "
     )
 }
 
-fn policy_user(user: uid_t, attribute: &str) -> String {
+fn policy_user(user: uid_t, show: &str) -> String {
     format!(
         "RULE
   EXTERNAL LIST 'size'
   EXEC ''
+  ESCAPE '{ESCAPE}'
 
 RULE 'TOTAL'
   LIST 'size'
   DIRECTORIES_PLUS
-  SHOW(VARCHAR({attribute}) || ' ' || VARCHAR(NLINK))
+  SHOW({show})
   WHERE USER_ID = {user}
 "
     )
 }
 
-fn policy_default(attribute: &str) -> String {
+fn policy_default(show: &str) -> String {
     format!(
         "RULE
   EXTERNAL LIST 'size'
   EXEC ''
+  ESCAPE '{ESCAPE}'
 
 RULE 'TOTAL'
   LIST 'size'
   DIRECTORIES_PLUS
-  SHOW(VARCHAR({attribute}) || ' ' || VARCHAR(NLINK))
+  SHOW({show})
 "
     )
 }
 
-// inode generation snapid  X Y Z -- path
-pub struct Entry<'a>(Vec<&'a [u8]>, &'a [u8]);
+/// The optional SHOW columns of the policy report.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Layout {
+    /// The other one of `FILE_SIZE` and `KB_ALLOCATED`.
+    pub other: bool,
+
+    /// The date and time of `--time`.
+    pub time: bool,
+
+    /// The user ID of `--columns owner`.
+    pub owner: bool,
+}
+
+impl From<&Config> for Layout {
+    fn from(config: &Config) -> Self {
+        Self {
+            // the ncdu export contains both apparent and allocated size
+            other: config.allocation || config.export_ncdu.is_some(),
+            time: config.time_mode.is_some(),
+            owner: config.columns.owner,
+        }
+    }
+}
+
+/// Identifies a file, e.g. to count hard links only once.
+///
+/// Inode numbers are unique only within a file system and may be reused, the
+/// same inode in different snapshots is a different file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileId {
+    pub device: u64,
+    pub inode: u64,
+    pub generation: u64,
+    pub snapshot: u64,
+}
+
+/// The type of a file, from the first character of its mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+/// A line of the policy report.
+///
+/// The fields are parsed without allocation, in this order:
+///
+/// ```text
+/// inode generation snapid  bytes nlink mode [other] [date time] [uid] -- path
+/// ```
+#[derive(Debug, PartialEq, Eq)]
+pub struct Entry<'a> {
+    pub inode: u64,
+    pub generation: u64,
+    pub snapshot: u64,
+    pub bytes: u64,
+    pub nlink: u64,
+    pub mode: &'a [u8],
+
+    /// The other one of `FILE_SIZE` and `KB_ALLOCATED` than `bytes`, only
+    /// present with `--allocation` or `--export-ncdu`.
+    pub other_bytes: Option<u64>,
+
+    /// The time, only present with `--time`.
+    pub time: Option<Timestamp>,
+
+    /// The user ID, only present with `--columns owner`.
+    pub uid: Option<uid_t>,
+
+    /// The percent-decoded path, see `ESCAPE`.
+    path: Cow<'a, Path>,
+}
 
 impl Entry<'_> {
     const INVALID: &'static str = "invalid line in policy report";
 
-    pub fn inode_str(&self) -> Result<&str> {
-        self.0[0].to_str().context("reading inode field")
+    /// Returns the identity of this file on the file system `device`.
+    pub const fn id(&self, device: u64) -> FileId {
+        FileId {
+            device,
+            inode: self.inode,
+            generation: self.generation,
+            snapshot: self.snapshot,
+        }
     }
 
-    pub fn bytes_str(&self) -> Result<&str> {
-        self.0[4].to_str().context("reading bytes field")
+    pub const fn kind(&self) -> Kind {
+        match self.mode.first() {
+            Some(b'-') => Kind::File,
+            Some(b'd') => Kind::Dir,
+            Some(b'l') => Kind::Symlink,
+            _ => Kind::Other,
+        }
     }
 
-    pub fn bytes(&self) -> Result<u64> {
-        self.bytes_str()
-            .and_then(|s| s.parse().context("parsing bytes field"))
+    pub fn is_dir(&self) -> bool {
+        self.kind() == Kind::Dir
     }
 
-    pub fn nlink_str(&self) -> Result<&str> {
-        self.0[5].to_str().context("reading number of links field")
+    /// Returns whether this may be one of several links to the same file.
+    ///
+    /// Directories always have more than one link, but cannot be hard links.
+    pub fn is_hard_link(&self) -> bool {
+        self.nlink > 1 && !self.is_dir()
     }
 
-    pub fn path(&self) -> Result<&Path> {
-        self.1.to_path().context("parsing path field")
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 }
 
-impl<'a> TryFrom<&'a [u8]> for Entry<'a> {
-    type Error = anyhow::Error;
-
-    fn try_from(line: &'a [u8]) -> Result<Self> {
+impl<'a> Entry<'a> {
+    /// Parses a line of a report with the optional columns of `layout`.
+    pub fn parse(line: &'a [u8], layout: Layout) -> Result<Self> {
         let Some((fields, path)) = line.split_once_str(" -- ") else {
-            return Err(anyhow!(
-                "{}: {}",
-                Entry::INVALID,
-                line.to_str_lossy()
-            ));
+            return Err(invalid(line, "missing path separator"));
+        };
+
+        let path = match decode(path) {
+            Some(Cow::Borrowed(path)) => {
+                Cow::Borrowed(Path::new(OsStr::from_bytes(path)))
+            }
+            Some(Cow::Owned(path)) => {
+                Cow::Owned(PathBuf::from(OsString::from_vec(path)))
+            }
+            None => return Err(invalid(line, "invalid path encoding")),
+        };
+
+        let mut fields = Fields {
+            line,
+            fields: fields.split(|b| *b == b' '),
+        };
+
+        let inode = fields.number("inode")?;
+        let generation = fields.number("generation")?;
+        let snapshot = fields.number("snapshot id")?;
+
+        // the attributes before the SHOW columns are not used
+        fields.next("attributes")?;
+
+        let bytes = fields.number("bytes")?;
+        let nlink = fields.number("number of links")?;
+        let mode = fields.next("mode")?;
+
+        if mode.is_empty() {
+            return Err(invalid(line, "empty mode field"));
+        }
+
+        let other_bytes = if layout.other {
+            Some(fields.number("other")?)
+        } else {
+            None
+        };
+
+        let time = if layout.time {
+            let date = fields.next("date")?;
+            let time = fields.next("time")?;
+            Some(fields.time(date, time)?)
+        } else {
+            None
+        };
+
+        let uid = if layout.owner {
+            let uid = fields.number("user ID")?;
+            let uid = uid_t::try_from(uid)
+                .map_err(|_| invalid(line, "invalid user ID field"))?;
+            Some(uid)
+        } else {
+            None
         };
 
-        let fields = fields.splitn_str(7, " ").take(6).collect::<Vec<_>>();
+        if fields.fields.next().is_some() {
+            return Err(invalid(line, "too many fields"));
+        }
+
+        Ok(Self {
+            inode,
+            generation,
+            snapshot,
+            bytes,
+            nlink,
+            mode,
+            other_bytes,
+            time,
+            uid,
+            path,
+        })
+    }
+}
+
+/// The space separated fields before the path.
+struct Fields<'a, I> {
+    line: &'a [u8],
+    fields: I,
+}
+
+impl<'a, I: Iterator<Item = &'a [u8]>> Fields<'a, I> {
+    fn next(&mut self, name: &str) -> Result<&'a [u8]> {
+        self.fields.next().ok_or_else(|| {
+            invalid(self.line, &format!("missing {name} field"))
+        })
+    }
 
-        if fields.len() == 6 {
-            Ok(Self(fields, path))
+    fn number(&mut self, name: &str) -> Result<u64> {
+        let field = self.next(name)?;
+        self.parse(field, name)
+    }
+
+    fn parse(&self, field: &[u8], name: &str) -> Result<u64> {
+        parse_u64(field).ok_or_else(|| {
+            invalid(self.line, &format!("invalid {name} field"))
+        })
+    }
+
+    fn time(&self, date: &[u8], time: &[u8]) -> Result<Timestamp> {
+        std::str::from_utf8(date)
+            .ok()
+            .zip(std::str::from_utf8(time).ok())
+            .and_then(|(date, time)| Timestamp::parse(date, time))
+            .ok_or_else(|| invalid(self.line, "invalid time field"))
+    }
+}
+
+fn invalid(line: &[u8], reason: &str) -> anyhow::Error {
+    anyhow!("{}: {reason}: {}", Entry::INVALID, line.to_str_lossy())
+}
+
+/// Decodes percent-encoded bytes, allocates only if there are any.
+pub fn decode(field: &[u8]) -> Option<Cow<'_, [u8]>> {
+    if !field.contains(&b'%') {
+        return Some(Cow::Borrowed(field));
+    }
+
+    let mut decoded = Vec::with_capacity(field.len());
+    let mut bytes = field.iter().copied();
+
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let high = hex(bytes.next()?)?;
+            let low = hex(bytes.next()?)?;
+            decoded.push(high << 4 | low);
         } else {
-            Err(anyhow!("{}: {}", Entry::INVALID, line.to_str_lossy()))
+            decoded.push(b);
         }
     }
+
+    Some(Cow::Owned(decoded))
+}
+
+const fn hex(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parses a decimal number without allocation.
+fn parse_u64(field: &[u8]) -> Option<u64> {
+    if field.is_empty() {
+        return None;
+    }
+
+    field.iter().try_fold(0_u64, |n, b| {
+        let digit = b.checked_sub(b'0').filter(|d| *d < 10)?;
+        n.checked_mul(10)?.checked_add(u64::from(digit))
+    })
 }
 
 // ----------------------------------------------------------------------------
@@ -151,22 +406,366 @@ impl<'a> TryFrom<&'a [u8]> for Entry<'a> {
 mod test {
     use super::*;
 
-    const ENTRY_SIMPLE: &str = "1 1 0  4096 1 -- /path/to/file";
-    const ENTRY_WITH_DASHES: &str = "1 1 0  4096 1 -- /path/with -- dashes";
+    const PLAIN: Layout = Layout {
+        other: false,
+        time: false,
+        owner: false,
+    };
+
+    const OTHER: Layout = Layout {
+        other: true,
+        ..PLAIN
+    };
+
+    const TIME: Layout = Layout {
+        time: true,
+        ..PLAIN
+    };
+
+    const BOTH: Layout = Layout {
+        other: true,
+        time: true,
+        ..PLAIN
+    };
+
+    const OWNER: Layout = Layout {
+        owner: true,
+        ..PLAIN
+    };
+
+    const ALL: Layout = Layout {
+        other: true,
+        time: true,
+        owner: true,
+    };
+
+    const LAYOUTS: [Layout; 6] = [PLAIN, OTHER, TIME, BOTH, OWNER, ALL];
+
+    const ENTRY_SIMPLE: &str = "1 1 0  4096 1 -rw-r--r-- -- /path/to/file";
+    const ENTRY_SNAPSHOT: &str =
+        "1 7 3  4096 1 -rw-r--r-- -- /path/.snapshots/x/to/file";
+    const ENTRY_WITH_DASHES: &str =
+        "1 1 0  4096 1 -rw-r--r-- -- /path/with -- dashes";
+    const ENTRY_DIR: &str = "1 1 0  4096 2 drwxr-xr-x -- /path/to/dir";
+    const ENTRY_WITH_TIME: &str =
+        "1 1 0  4096 1 -rw-r--r-- 2024-05-01 12:34:56.789 -- /path/to/file";
+    const ENTRY_WITH_NEWLINE: &str =
+        "1 1 0  4096 1 -rw-r--r-- -- /path/with%0Anewline";
+    const ENTRY_WITH_ESCAPED_DASHES: &str =
+        "1 1 0  4096 1 -rw-r--r-- -- /path/with%20--%20dashes";
+    const ENTRY_WITH_INVALID_UTF8: &str =
+        "1 1 0  4096 1 -rw-r--r-- -- /path/%FF%fe%25";
 
     #[test]
     fn parse_entry() {
-        let entry = Entry::try_from(ENTRY_SIMPLE.as_bytes()).unwrap();
+        let entry = Entry::parse(ENTRY_SIMPLE.as_bytes(), PLAIN).unwrap();
+
+        assert_eq!(entry.inode, 1);
+        assert_eq!(entry.bytes, 4096);
+        assert_eq!(entry.nlink, 1);
+        assert_eq!(entry.mode, b"-rw-r--r--");
+        assert!(!entry.is_dir());
+        assert_eq!(entry.time, None);
+        assert_eq!(entry.path(), Path::new("/path/to/file"));
+    }
+
+    #[test]
+    fn parse_entry_with_time() {
+        let entry = Entry::parse(ENTRY_WITH_TIME.as_bytes(), TIME).unwrap();
+
+        assert_eq!(entry.time, Timestamp::parse("2024-05-01", "12:34:56"));
+        assert_eq!(entry.path(), Path::new("/path/to/file"));
+    }
+
+    #[test]
+    fn parse_entry_with_other_bytes() {
+        let line = "1 1 0  4096 1 -rw-r--r-- 8 -- /path/to/file";
+        let entry = Entry::parse(line.as_bytes(), OTHER).unwrap();
+
+        assert_eq!(entry.bytes, 4096);
+        assert_eq!(entry.other_bytes, Some(8));
+        assert_eq!(entry.time, None);
 
-        assert_eq!(entry.inode_str().unwrap(), "1");
-        assert_eq!(entry.bytes_str().unwrap(), "4096");
-        assert_eq!(entry.nlink_str().unwrap(), "1");
-        assert_eq!(entry.path().unwrap(), Path::new("/path/to/file"));
+        let line = "1 1 0  4096 1 -rw-r--r-- 8 2024-05-01 12:34:56 -- /file";
+        let entry = Entry::parse(line.as_bytes(), BOTH).unwrap();
+
+        assert_eq!(entry.other_bytes, Some(8));
+        assert_eq!(entry.time, Timestamp::parse("2024-05-01", "12:34:56"));
+
+        let entry = Entry::parse(ENTRY_WITH_TIME.as_bytes(), TIME).unwrap();
+        assert_eq!(entry.other_bytes, None);
+    }
+
+    #[test]
+    fn parse_entry_with_owner() {
+        let line = "1 1 0  4096 1 -rw-r--r-- 1000 -- /path/to/file";
+        let entry = Entry::parse(line.as_bytes(), OWNER).unwrap();
+
+        assert_eq!(entry.uid, Some(1000));
+        assert_eq!(entry.other_bytes, None);
+
+        let line = "1 1 0  4096 1 -rw-r--r-- 8 2024-05-01 12:34:56 0 -- /file";
+        let entry = Entry::parse(line.as_bytes(), ALL).unwrap();
+
+        assert_eq!(entry.other_bytes, Some(8));
+        assert_eq!(entry.time, Timestamp::parse("2024-05-01", "12:34:56"));
+        assert_eq!(entry.uid, Some(0));
+
+        let entry = Entry::parse(ENTRY_SIMPLE.as_bytes(), PLAIN).unwrap();
+        assert_eq!(entry.uid, None);
+
+        let line = "1 1 0  4096 1 -rw-r--r-- 4294967296 -- /path/to/file";
+        assert!(Entry::parse(line.as_bytes(), OWNER).is_err());
+    }
+
+    #[test]
+    fn parse_entry_layout() {
+        let mismatches = [
+            (ENTRY_SIMPLE, OTHER),
+            (ENTRY_SIMPLE, TIME),
+            (ENTRY_WITH_TIME, PLAIN),
+            (ENTRY_WITH_TIME, OTHER),
+            (ENTRY_WITH_TIME, BOTH),
+            ("1 1 0  4096 1 -rw-r--r-- 8 -- /path/to/file", PLAIN),
+            ("1 1 0  4096 1 -rw-r--r-- 8 -- /path/to/file", TIME),
+            ("1 1 0  4096 1 -rw-r--r-- 8 2024-05-01 12:34 -- /file", TIME),
+            (ENTRY_WITH_TIME, ALL),
+        ];
+
+        for (line, layout) in mismatches {
+            let error = Entry::parse(line.as_bytes(), layout).unwrap_err();
+            let error = error.to_string();
+
+            assert!(error.starts_with(Entry::INVALID), "{line:?}: {error}");
+        }
+    }
+
+    #[test]
+    fn parse_entry_id() {
+        let entry = Entry::parse(ENTRY_SIMPLE.as_bytes(), PLAIN).unwrap();
+        let snapshot = Entry::parse(ENTRY_SNAPSHOT.as_bytes(), PLAIN).unwrap();
+
+        let id = FileId {
+            device: 42,
+            inode: 1,
+            generation: 1,
+            snapshot: 0,
+        };
+
+        assert_eq!(entry.id(42), id);
+        assert_ne!(entry.id(43), id);
+
+        assert_eq!(
+            snapshot.id(42),
+            FileId {
+                generation: 7,
+                snapshot: 3,
+                ..id
+            }
+        );
+    }
+
+    #[test]
+    fn parse_entry_dir() {
+        let entry = Entry::parse(ENTRY_DIR.as_bytes(), PLAIN).unwrap();
+        assert!(entry.is_dir());
+        assert!(!entry.is_hard_link());
+
+        let line = "1 1 0  4096 2 -rw-r--r-- -- /path/to/file";
+        let entry = Entry::parse(line.as_bytes(), PLAIN).unwrap();
+        assert!(entry.is_hard_link());
+    }
+
+    #[test]
+    fn parse_entry_kind() {
+        for (mode, kind) in [
+            ("-rw-r--r--", Kind::File),
+            ("drwxr-xr-x", Kind::Dir),
+            ("lrwxrwxrwx", Kind::Symlink),
+            ("srwxr-xr-x", Kind::Other),
+            ("prw-r--r--", Kind::Other),
+        ] {
+            let line = format!("1 1 0  4096 1 {mode} -- /path/to/file");
+            let entry = Entry::parse(line.as_bytes(), PLAIN).unwrap();
+            assert_eq!(entry.kind(), kind, "{mode}");
+        }
     }
 
     #[test]
     fn parse_entry_with_dashes() {
-        let entry = Entry::try_from(ENTRY_WITH_DASHES.as_bytes()).unwrap();
-        assert_eq!(entry.path().unwrap(), Path::new("/path/with -- dashes"));
+        let entry = Entry::parse(ENTRY_WITH_DASHES.as_bytes(), PLAIN).unwrap();
+        assert_eq!(entry.path(), Path::new("/path/with -- dashes"));
+    }
+
+    #[test]
+    fn parse_entry_escaped() {
+        let entry =
+            Entry::parse(ENTRY_WITH_NEWLINE.as_bytes(), PLAIN).unwrap();
+        assert_eq!(entry.path(), Path::new("/path/with\nnewline"));
+
+        let entry =
+            Entry::parse(ENTRY_WITH_ESCAPED_DASHES.as_bytes(), PLAIN).unwrap();
+        assert_eq!(entry.path(), Path::new("/path/with -- dashes"));
+
+        let entry =
+            Entry::parse(ENTRY_WITH_INVALID_UTF8.as_bytes(), PLAIN).unwrap();
+        assert_eq!(entry.path().as_os_str().as_bytes(), b"/path/\xff\xfe%");
+    }
+
+    #[test]
+    fn parse_entry_invalid_escape() {
+        for path in ["/path/%", "/path/%F", "/path/%FG", "/path/%%41"] {
+            let line = format!("1 1 0  4096 1 -rw-r--r-- -- {path}");
+            assert!(Entry::parse(line.as_bytes(), PLAIN).is_err(), "{path}");
+        }
+    }
+
+    #[test]
+    fn parse_entry_invalid() {
+        let invalid = [
+            "",
+            "1 1 0  3 -- /data/test/bar",
+            "1 1 0  4096 1 -rw-r--r-- /path/without/separator",
+            "1 1 0 4096 1 -rw-r--r-- -- /path/missing/attributes",
+            "x 1 0  4096 1 -rw-r--r-- -- /path/to/file",
+            "1 1 0  -4096 1 -rw-r--r-- -- /path/to/file",
+            "1 1 0  18446744073709551616 1 -rw-r--r-- -- /path/to/file",
+            "1 1 0  4096 1  -- /path/to/file",
+            "1 1 0  4096 1 -rw-r--r-- 2024-05-01 -- /path/to/file",
+            "1 1 0  4096 1 -rw-r--r-- 2024-13-01 12:34 -- /path/to/file",
+            "1 1 0  4096 1 -rw-r--r-- 2024-05-01 12:34 x -- /path/to/file",
+            "1 1 0  4096 1 -rw-r--r-- 8 2024-05-01 12:34 x -- /path/to/file",
+            "1 1 0  4096 1 -rw-r--r-- x -- /path/to/file",
+        ];
+
+        for line in invalid {
+            let error = Entry::parse(line.as_bytes(), PLAIN).unwrap_err();
+            let error = error.to_string();
+
+            assert!(error.starts_with(Entry::INVALID), "{line:?}: {error}");
+        }
+    }
+
+    #[test]
+    fn parse_number() {
+        assert_eq!(parse_u64(b"0"), Some(0));
+        assert_eq!(parse_u64(b"4096"), Some(4096));
+        assert_eq!(parse_u64(b"18446744073709551615"), Some(u64::MAX));
+        assert_eq!(parse_u64(b"18446744073709551616"), None);
+        assert_eq!(parse_u64(b""), None);
+        assert_eq!(parse_u64(b"+1"), None);
+        assert_eq!(parse_u64(b"1 "), None);
+    }
+
+    // ------------------------------------------------------------------------
+    // fuzzing malformed lines, these must result in errors, never in panics
+    // ------------------------------------------------------------------------
+
+    const VALID: [&str; 8] = [
+        ENTRY_SIMPLE,
+        ENTRY_SNAPSHOT,
+        ENTRY_WITH_DASHES,
+        ENTRY_DIR,
+        ENTRY_WITH_TIME,
+        ENTRY_WITH_NEWLINE,
+        ENTRY_WITH_ESCAPED_DASHES,
+        ENTRY_WITH_INVALID_UTF8,
+    ];
+
+    /// Simple xorshift generator, good enough to produce garbage.
+    struct Garbage(u64);
+
+    impl Garbage {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            usize::try_from(self.next() % n as u64).unwrap()
+        }
+    }
+
+    #[test]
+    fn fuzz_truncated() {
+        for line in VALID {
+            let line = line.as_bytes();
+
+            for end in 0..line.len() {
+                for layout in LAYOUTS {
+                    let _ = Entry::parse(&line[..end], layout);
+                    let _ = Entry::parse(&line[end..], layout);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fuzz_mutated() {
+        const ALPHABET: &[u8] = b" -0123456789:.%dx\xff";
+
+        let mut garbage = Garbage(0x2545_f491_4f6c_dd1d);
+
+        for _ in 0..10_000 {
+            let mut line =
+                VALID[garbage.below(VALID.len())].as_bytes().to_vec();
+
+            for _ in 0..=garbage.below(4) {
+                let index = garbage.below(line.len());
+
+                match garbage.below(3) {
+                    0 => {
+                        line.remove(index);
+                    }
+                    1 => {
+                        let b = ALPHABET[garbage.below(ALPHABET.len())];
+                        line.insert(index, b);
+                    }
+                    _ => line[index] = garbage.next().to_le_bytes()[0],
+                }
+            }
+
+            let layout = LAYOUTS[garbage.below(LAYOUTS.len())];
+
+            if let Ok(entry) = Entry::parse(line.as_slice(), layout) {
+                assert!(!entry.mode.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn fuzz_dates() {
+        for date in [
+            "9223372036854775807-12-31",
+            "-9223372036854775808-01-01",
+            "99999999999-01-01",
+            "2024-02-31",
+            "2024-00-10",
+            "2024-01-00",
+        ] {
+            let line =
+                format!("1 1 0  4096 1 -rw-r--r-- {date} 12:34:56 -- /file");
+
+            assert!(Entry::parse(line.as_bytes(), TIME).is_err(), "{date}");
+        }
+    }
+
+    #[test]
+    fn fuzz_random() {
+        let mut garbage = Garbage(0x9e37_79b9_7f4a_7c15);
+
+        for _ in 0..10_000 {
+            let len = garbage.below(64);
+
+            let line: Vec<u8> = (0..len)
+                .map(|_| b" -0123456789"[garbage.below(12)])
+                .collect();
+
+            for layout in LAYOUTS {
+                let _ = Entry::parse(line.as_slice(), layout);
+            }
+        }
     }
 }
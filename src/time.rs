@@ -0,0 +1,208 @@
+/* * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * *
+ *                                                                           *
+ *  Copyright  (C)  2019-2024  Christian Krause                              *
+ *                                                                           *
+ *  Christian Krause  <christian.krause@idiv.de>                             *
+ *                                                                           *
+ * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * *
+ *                                                                           *
+ *  This file is part of mmdu.                                               *
+ *                                                                           *
+ *  mmdu is free software: you can redistribute it and/or modify             *
+ *  it under the terms of the GNU General Public License as published by     *
+ *  the Free Software Foundation, either version 3 of the license, or any    *
+ *  later version.                                                           *
+ *                                                                           *
+ *  mmdu is distributed in the hope that it will be useful, but              *
+ *  WITHOUT ANY WARRANTY; without even the implied warranty of               *
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU         *
+ *  General Public License for more details.                                 *
+ *                                                                           *
+ *  You should have received a copy of the GNU General Public License along  *
+ *  with mmdu. If not, see <http://www.gnu.org/licenses/>.                   *
+ *                                                                           *
+ * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * */
+
+use std::fmt;
+
+/// A point in time as reported by the policy engine.
+///
+/// This is stored as seconds since the epoch, but without any time zone
+/// conversion, i.e. it is displayed in the same time zone as it was parsed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp(i64);
+
+impl Timestamp {
+    pub const fn from_seconds(seconds: i64) -> Self {
+        Self(seconds)
+    }
+
+    pub const fn seconds(self) -> i64 {
+        self.0
+    }
+
+    /// Parses date `YYYY-MM-DD` and time `HH:MM:SS[.ffffff]`.
+    pub fn parse(date: &str, time: &str) -> Option<Self> {
+        let mut date = date.splitn(3, '-');
+        let year = date.next()?.parse::<i64>().ok()?;
+        let month = date.next()?.parse::<i64>().ok()?;
+        let day = date.next()?.parse::<i64>().ok()?;
+
+        let time = time.split_once('.').map_or(time, |(time, _)| time);
+        let mut time = time.splitn(3, ':');
+        let hour = time.next()?.parse::<i64>().ok()?;
+        let minute = time.next()?.parse::<i64>().ok()?;
+        let second = time.next()?.parse::<i64>().ok()?;
+
+        // the policy engine reports four-digit years, this also keeps the
+        // arithmetic below far from overflowing
+        let valid = (0..=9999).contains(&year)
+            && (1..=12).contains(&month)
+            && (1..=days_in_month(year, month)).contains(&day)
+            && (0..24).contains(&hour)
+            && (0..60).contains(&minute)
+            && (0..=60).contains(&second);
+
+        valid.then(|| {
+            let days = days_from_civil(year, month, day);
+            Self(days * 86400 + hour * 3600 + minute * 60 + second)
+        })
+    }
+
+    /// Returns ISO 8601 date and time `YYYY-MM-DDTHH:MM:SS` without a time
+    /// zone, i.e. in the time zone of the policy engine.
+    pub fn iso8601(self) -> String {
+        let (year, month, day, hour, minute, second) = self.civil();
+        format!(
+            "{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}"
+        )
+    }
+
+    const fn civil(self) -> (i64, i64, i64, i64, i64, i64) {
+        let days = self.0.div_euclid(86400);
+        let seconds = self.0.rem_euclid(86400);
+
+        let (year, month, day) = civil_from_days(days);
+
+        (
+            year,
+            month,
+            day,
+            seconds / 3600,
+            seconds % 3600 / 60,
+            seconds % 60,
+        )
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (year, month, day, hour, minute, _) = self.civil();
+        write!(f, "{year:04}-{month:02}-{day:02} {hour:02}:{minute:02}")
+    }
+}
+
+// ----------------------------------------------------------------------------
+// calendar, see http://howardhinnant.github.io/date_algorithms.html
+// ----------------------------------------------------------------------------
+
+const fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+const fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146_097 + doe - 719_468
+}
+
+const fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400;
+
+    if month <= 2 {
+        (year + 1, month, day)
+    } else {
+        (year, month, day)
+    }
+}
+
+// ----------------------------------------------------------------------------
+// tests
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Some(Timestamp(0)),
+            Timestamp::parse("1970-01-01", "00:00:00")
+        );
+
+        assert_eq!(
+            Some(Timestamp(951_782_400 + 3723)),
+            Timestamp::parse("2000-02-29", "01:02:03.456789")
+        );
+
+        let ts = Timestamp::parse("9999-12-31", "23:59:59").unwrap();
+        assert_eq!("9999-12-31T23:59:59", ts.iso8601());
+
+        assert_eq!(None, Timestamp::parse("2000-13-01", "00:00:00"));
+        assert_eq!(None, Timestamp::parse("2024-02-31", "00:00:00"));
+        assert_eq!(None, Timestamp::parse("2024-04-31", "00:00:00"));
+        assert_eq!(None, Timestamp::parse("2023-02-29", "00:00:00"));
+        assert_eq!(None, Timestamp::parse("1900-02-29", "00:00:00"));
+        assert_eq!(None, Timestamp::parse("10000-01-01", "00:00:00"));
+        assert_eq!(None, Timestamp::parse("-1-01-01", "00:00:00"));
+        assert_eq!(
+            None,
+            Timestamp::parse("9223372036854775807-12-31", "23:59:59")
+        );
+        assert_eq!(None, Timestamp::parse("2000-01-01", "00:00"));
+        assert_eq!(None, Timestamp::parse("2000-01", "00:00:00"));
+    }
+
+    #[test]
+    fn display() {
+        let ts = Timestamp::parse("2024-12-31", "23:59:59").unwrap();
+        assert_eq!("2024-12-31 23:59", ts.to_string());
+
+        let ts = Timestamp::parse("1969-07-20", "20:17:40").unwrap();
+        assert_eq!("1969-07-20 20:17", ts.to_string());
+    }
+
+    #[test]
+    fn iso8601() {
+        let ts = Timestamp::parse("2024-12-31", "23:59:59.5").unwrap();
+        assert_eq!("2024-12-31T23:59:59", ts.iso8601());
+
+        let ts = Timestamp::parse("1969-07-20", "20:17:40").unwrap();
+        assert_eq!("1969-07-20T20:17:40", ts.iso8601());
+    }
+
+    #[test]
+    fn order() {
+        let old = Timestamp::parse("2019-01-01", "00:00:00").unwrap();
+        let new = Timestamp::parse("2019-01-01", "00:00:01").unwrap();
+        assert!(old < new);
+    }
+}
@@ -23,93 +23,295 @@
  *                                                                           *
  * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * */
 
-use std::collections::{BTreeMap, HashMap};
-use std::io::{BufReader, Read};
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
+use std::sync::{PoisonError, RwLock};
 
-use anyhow::{Context, Result};
-use bstr::io::BufReadExt;
+use anyhow::Result;
 
+use crate::config::Config;
 use crate::policy::Entry;
-use crate::usage::Acc;
+use crate::usage::links::HardLinks;
+use crate::usage::report::{self, Report};
+use crate::usage::tree::{Item, Tree};
+use crate::usage::{Acc, DirTotal, GrandTotal};
 
-struct DepthAcc {
-    acc: Acc,
-    hard_links: HashMap<String, u64>,
+/// State of each thread parsing the report.
+struct State {
+    /// The nodes of the entries of this thread along with their sums.
+    tree: Tree,
+    hard_links: HardLinks,
+    total: Option<DirTotal>,
+
+    /// Nodes of the previous path, starting with the root.
+    ancestors: Vec<u32>,
+
+    /// The previous path.
+    last: PathBuf,
 }
 
-impl DepthAcc {
-    fn new(bytes: u64) -> Self {
-        Self {
-            acc: Acc::new(bytes),
-            hard_links: HashMap::new(),
+impl State {
+    /// Looks up the nodes of `path` up to `depth` below the DIR.
+    fn visit(&mut self, path: &Path, prefix_depth: usize, depth: usize) {
+        let suffix = path.iter().skip(prefix_depth).take(depth);
+
+        // consecutive lines are mostly in the same directory
+        let shared = suffix
+            .clone()
+            .zip(self.last.iter().skip(prefix_depth))
+            .take_while(|(name, last)| name == last)
+            .count();
+
+        self.ancestors.truncate(shared + 1);
+
+        for name in suffix.skip(shared) {
+            let parent = self.ancestors[self.ancestors.len() - 1];
+            let node = self.tree.child(parent, name);
+            self.ancestors.push(node);
+        }
+
+        let last = self.last.as_mut_os_string();
+        last.clear();
+        last.push(path);
+    }
+
+    /// Records the attributes of `entry` for its own `node`.
+    fn attributes(
+        &mut self,
+        entry: &Entry,
+        node: u32,
+        config: &Config,
+        items: bool,
+    ) {
+        let node = &mut self.tree[node];
+
+        if config.empty_dirs && entry.is_dir() {
+            node.dir = true;
+        }
+
+        if let Some(uid) = entry.uid {
+            node.acc.owner = Some(uid);
+        }
+
+        if items {
+            node.item = Some(Item::new(entry, config));
         }
     }
 }
 
-pub fn sum(
+/// Looks up the nodes of `path` up to `depth` below the DIR in the tree shared
+/// by all threads, starting with the root.
+///
+/// Only hard linked files need nodes that are the same for all threads, so
+/// the lock is rarely taken.
+fn shared_nodes(
+    tree: &RwLock<Tree>,
+    path: &Path,
+    prefix_depth: usize,
+    depth: usize,
+) -> Vec<u32> {
+    let names = path
+        .iter()
+        .skip(prefix_depth)
+        .take(depth)
+        .collect::<Vec<_>>();
+
+    let mut nodes = vec![Tree::ROOT];
+
+    {
+        let tree = tree.read().unwrap_or_else(PoisonError::into_inner);
+
+        while let Some(node) = names
+            .get(nodes.len() - 1)
+            .and_then(|name| tree.get(nodes[nodes.len() - 1], name))
+        {
+            nodes.push(node);
+        }
+    }
+
+    if nodes.len() <= names.len() {
+        let mut tree = tree.write().unwrap_or_else(PoisonError::into_inner);
+
+        for name in &names[nodes.len() - 1..] {
+            let node = tree.child(nodes[nodes.len() - 1], name);
+            nodes.push(node);
+        }
+
+        drop(tree);
+    }
+
+    nodes
+}
+
+pub fn sum<R: Report + ?Sized>(
     dir: &Path,
     depth: usize,
-    report: impl Read,
-    count_links: bool,
-) -> Result<BTreeMap<PathBuf, Acc>> {
-    let report = BufReader::new(report);
+    report: &R,
+    device: u64,
+    config: &Config,
+    total: Option<&mut GrandTotal>,
+) -> Result<(BTreeMap<PathBuf, Acc>, u64)> {
+    let (tree, skipped) =
+        tree(dir, depth, report, device, config, total, false)?;
 
-    let mut sums: HashMap<PathBuf, DepthAcc> = HashMap::new();
+    Ok((select(&tree, depth, config, skipped), skipped))
+}
+
+/// Returns the tree of `dir` up to `depth` with the sums of all nodes, along
+/// with the number of skipped lines. With `items`, the attributes of the
+/// entries are recorded as well, see `--export-ncdu`.
+pub fn tree<R: Report + ?Sized>(
+    dir: &Path,
+    depth: usize,
+    report: &R,
+    device: u64,
+    config: &Config,
+    mut total: Option<&mut GrandTotal>,
+    items: bool,
+) -> Result<(Tree, u64)> {
+    let Config {
+        count_links,
+        all,
+        separate_dirs,
+        ..
+    } = *config;
+
+    let with_total = total.is_some();
+
+    // every thread builds the tree of its own entries, these are merged in
+    // the end, only the nodes of hard linked files are shared by all threads
+    let shared = RwLock::new(Tree::new(dir));
 
     let prefix_depth = dir.iter().count();
 
-    for line in report.byte_lines() {
-        let line = line.context("reading line from policy report")?;
-        let entry = Entry::try_from(line.as_slice())
-            .context("parsing line from policy report")?;
+    let folded = report::fold(
+        report,
+        config,
+        || {
+            Ok(State {
+                tree: Tree::new(dir),
+                hard_links: HardLinks::for_thread(config),
+                total: with_total.then(|| DirTotal::new(config)),
+                ancestors: vec![Tree::ROOT],
+                last: PathBuf::new(),
+            })
+        },
+        |state, entry| {
+            let acc = Acc::from_entry(entry, config);
+            let id = entry.id(device);
 
-        let bytes = entry.bytes()?;
-        let nlink = entry.nlink_str()?;
-        let inode = entry.inode_str()?;
+            if let Some(total) = &mut state.total {
+                total.add(entry, id, acc, count_links)?;
+            }
 
-        let path = entry.path()?;
-        let path_depth = path.iter().count();
-        let path_suffix_depth = path_depth - prefix_depth;
+            let path = entry.path();
 
-        for depth in 0..=depth.min(path_suffix_depth) {
-            let prefix: PathBuf =
-                path.iter().take(prefix_depth + depth).collect();
+            // anything deeper than the parent of the depth limit is irrelevant
+            let path_suffix_depth =
+                path.iter().skip(prefix_depth).take(depth + 2).count();
 
-            if count_links || nlink == "1" {
-                sums.entry(prefix)
-                    .and_modify(|v| v.acc += bytes)
-                    .or_insert_with(|| DepthAcc::new(bytes));
+            state.visit(path, prefix_depth, depth);
+
+            if path_suffix_depth <= depth {
+                let node = state.ancestors[path_suffix_depth];
+                state.attributes(entry, node, config, items);
+            }
+
+            // with --separate-dirs, directories account only for themselves
+            // and files for their parent directory, otherwise all ancestors
+            let depths = if !separate_dirs {
+                0..=path_suffix_depth
+            } else if entry.is_dir() {
+                path_suffix_depth..=path_suffix_depth
+            } else if all {
+                path_suffix_depth.saturating_sub(1)..=path_suffix_depth
             } else {
-                sums.entry(prefix)
-                    .and_modify(|v| {
-                        let inode = v
-                            .hard_links
-                            .entry(inode.to_owned())
-                            .and_modify(|c| *c += 1)
-                            .or_insert(1);
-
-                        if *inode == 1 {
-                            v.acc += bytes;
-                        }
-                    })
-                    .or_insert_with(|| {
-                        let mut hard_links = HashMap::new();
-                        hard_links.insert(inode.to_owned(), 1);
-
-                        DepthAcc {
-                            acc: Acc::new(bytes),
-                            hard_links,
-                        }
-                    });
+                let parent_depth = path_suffix_depth.saturating_sub(1);
+                parent_depth..=parent_depth
+            };
+
+            let depths = depths.filter(|d| *d <= depth);
+
+            if count_links || !entry.is_hard_link() {
+                for depth in depths {
+                    let node = state.ancestors[depth];
+                    state.tree[node].acc += acc;
+                }
+            } else {
+                let nodes = shared_nodes(&shared, path, prefix_depth, depth);
+
+                for depth in depths {
+                    state.hard_links.insert(id, nodes[depth], acc)?;
+                }
             }
+
+            Ok(())
+        },
+    )?;
+
+    let mut tree = shared.into_inner().unwrap_or_else(PoisonError::into_inner);
+
+    let mut hard_links = HardLinks::for_thread(config);
+    let mut dir_total = DirTotal::new(config);
+
+    for state in folded.states {
+        tree.merge(state.tree);
+
+        hard_links.merge(state.hard_links)?;
+
+        if let Some(total) = state.total {
+            dir_total.merge(total)?;
         }
     }
 
-    Ok(sums
-        .into_iter()
-        .filter_map(|(path, v)| (v.acc.inodes > 1).then_some((path, v.acc)))
-        .collect())
+    GrandTotal::finish_links(
+        total.as_deref_mut(),
+        hard_links,
+        |_, node, acc| {
+            tree[node].acc += acc;
+        },
+    )?;
+
+    dir_total.acc.skipped += folded.skipped;
+
+    if let Some(total) = total {
+        total.add_pending(dir_total)?;
+    }
+
+    Ok((tree, folded.skipped))
+}
+
+/// Returns the sums of the relevant nodes up to `depth` by path.
+pub fn select(
+    tree: &Tree,
+    depth: usize,
+    config: &Config,
+    skipped: u64,
+) -> BTreeMap<PathBuf, Acc> {
+    let all = config.all || config.separate_dirs;
+
+    // without --all, only directories with content are of interest, files
+    // within the depth limit only ever account for themselves, which is not
+    // the case with --separate-dirs, where files account only for parents,
+    // with --empty-dirs, directories are of interest regardless
+    tree.nodes()
+        .filter(|(_, node)| {
+            let inodes = node.acc.inodes;
+            inodes > 1
+                || (all && inodes > 0)
+                || (config.empty_dirs && node.dir)
+        })
+        .filter(|(index, _)| tree.depth(*index) <= depth)
+        .map(|(index, node)| {
+            (
+                tree.path(index),
+                Acc {
+                    skipped,
+                    ..node.acc
+                },
+            )
+        })
+        .collect()
 }
 
 // ----------------------------------------------------------------------------
@@ -121,18 +323,30 @@ mod test {
     use indoc::indoc;
 
     use super::*;
+    use crate::usage::MAX_DEPTH;
+
+    /// Returns the sums of `/data/test` in `source` up to `--max-depth`.
+    fn run(args: &[&str], source: &str) -> BTreeMap<PathBuf, Acc> {
+        let config = Config::from_args(args);
+        let depth = config.max_depth.unwrap_or_default();
+        let dir = Path::new("/data/test");
+
+        sum(dir, depth, source.as_bytes(), 0, &config, None)
+            .unwrap()
+            .0
+    }
 
     const SOURCE: &str = indoc! {"
-        1 1 0  4096 1 -- /data/test
-        1 1 0  1024 5 -- /data/test/foo
-        1 1 0  1024 5 -- /data/test/bar
-        2 1 0  1024 2 -- /data/test/other
-        1 1 0  4096 1 -- /data/test/a
-        1 1 0  1024 5 -- /data/test/a/foo
-        1 1 0  1024 5 -- /data/test/a/bar
-        1 1 0  4096 1 -- /data/test/b
-        1 1 0  1024 5 -- /data/test/b/foo
-        2 1 0  1024 2 -- /data/test/b/other
+        1 1 0  4096 1 drwxr-xr-x -- /data/test
+        1 1 0  1024 5 -rw-r--r-- -- /data/test/foo
+        1 1 0  1024 5 -rw-r--r-- -- /data/test/bar
+        2 1 0  1024 2 -rw-r--r-- -- /data/test/other
+        1 1 0  4096 1 drwxr-xr-x -- /data/test/a
+        1 1 0  1024 5 -rw-r--r-- -- /data/test/a/foo
+        1 1 0  1024 5 -rw-r--r-- -- /data/test/a/bar
+        1 1 0  4096 1 drwxr-xr-x -- /data/test/b
+        1 1 0  1024 5 -rw-r--r-- -- /data/test/b/foo
+        2 1 0  1024 2 -rw-r--r-- -- /data/test/b/other
     "};
 
     #[test]
@@ -142,8 +356,7 @@ mod test {
         expected.insert("/data/test/a".into(), Acc::from((2, 5120)));
         expected.insert("/data/test/b".into(), Acc::from((3, 6144)));
 
-        let sum =
-            sum(Path::new("/data/test"), 1, SOURCE.as_bytes(), false).unwrap();
+        let sum = run(&["-d1"], SOURCE);
 
         assert_eq!(expected, sum);
     }
@@ -155,9 +368,235 @@ mod test {
         expected.insert("/data/test/a".into(), Acc::from((3, 6144)));
         expected.insert("/data/test/b".into(), Acc::from((3, 6144)));
 
-        let sum =
-            sum(Path::new("/data/test"), 1, SOURCE.as_bytes(), true).unwrap();
+        let sum = run(&["-d1", "--count-links"], SOURCE);
 
         assert_eq!(expected, sum);
     }
+
+    #[test]
+    fn parse_all() {
+        let mut expected = BTreeMap::new();
+        expected.insert("/data/test".into(), Acc::from((5, 14336)));
+        expected.insert("/data/test/foo".into(), Acc::from((1, 1024)));
+        expected.insert("/data/test/bar".into(), Acc::from((1, 1024)));
+        expected.insert("/data/test/other".into(), Acc::from((1, 1024)));
+        expected.insert("/data/test/a".into(), Acc::from((2, 5120)));
+        expected.insert("/data/test/b".into(), Acc::from((3, 6144)));
+
+        let sum = run(&["-d1", "--all"], SOURCE);
+
+        assert_eq!(expected, sum);
+    }
+
+    #[test]
+    fn parse_separate_dirs() {
+        let mut expected = BTreeMap::new();
+        expected.insert("/data/test".into(), Acc::from((3, 6144)));
+        expected.insert("/data/test/a".into(), Acc::from((2, 5120)));
+        expected.insert("/data/test/b".into(), Acc::from((3, 6144)));
+
+        let sum = run(&["-d1", "--separate-dirs"], SOURCE);
+
+        assert_eq!(expected, sum);
+    }
+
+    #[test]
+    fn parse_owners() {
+        let source = indoc! {"
+            1 1 0  4096 2 drwxr-xr-x 0 -- /data/test
+            2 1 0  1024 1 -rw-r--r-- 1000 -- /data/test/foo
+            3 1 0  4096 2 drwxr-xr-x 1000 -- /data/test/a
+            4 1 0  1024 1 -rw-r--r-- 1001 -- /data/test/a/bar
+            5 1 0  1024 1 -rw-r--r-- 1002 -- /data/test/b/baz
+            6 1 0  1024 1 -rw-r--r-- 1002 -- /data/test/b/qux
+        "};
+
+        let sum = run(&["-d1", "--columns=owner"], source);
+
+        let owner = |path: &str| sum[Path::new(path)].owner;
+
+        // the owner of the entry itself, if it is in the report
+        assert_eq!(Some(0), owner("/data/test"));
+        assert_eq!(Some(1000), owner("/data/test/a"));
+        assert_eq!(None, owner("/data/test/b"));
+    }
+
+    #[test]
+    fn parse_separate_dirs_depth() {
+        let mut expected = BTreeMap::new();
+        expected.insert("/data/test".into(), Acc::from((3, 6144)));
+
+        let sum = run(&["-d0", "--separate-dirs"], SOURCE);
+
+        assert_eq!(expected, sum);
+    }
+
+    #[test]
+    fn select_full_tree() {
+        let dir = Path::new("/data/test");
+        let source = SOURCE.as_bytes();
+
+        for args in [
+            &["-d1"][..],
+            &["-d1", "--all"],
+            &["-d1", "--separate-dirs"],
+            &["-d1", "--empty-dirs"],
+            &["-d2", "--all"],
+        ] {
+            let config = Config::from_args(args);
+            let depth = config.max_depth.unwrap();
+
+            let (full, skipped) =
+                tree(dir, MAX_DEPTH, source, 0, &config, None, true).unwrap();
+
+            assert_eq!(
+                run(args, SOURCE),
+                select(&full, depth, &config, skipped),
+                "{args:?}"
+            );
+        }
+    }
+
+    const SOURCE_ESCAPED: &str = indoc! {"
+        1 1 0  4096 1 drwxr-xr-x -- /data/test
+        2 1 0  4096 1 drwxr-xr-x -- /data/test/new%0Aline
+        3 1 0  1024 1 -rw-r--r-- -- /data/test/new%0Aline/foo
+        4 1 0  4096 1 drwxr-xr-x -- /data/test/dashes%20--%20here
+        5 1 0  1024 1 -rw-r--r-- -- /data/test/dashes%20--%20here/foo
+        6 1 0  4096 1 drwxr-xr-x -- /data/test/%FF
+        7 1 0  1024 1 -rw-r--r-- -- /data/test/%FF/foo
+    "};
+
+    #[test]
+    fn parse_escaped() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut expected = BTreeMap::new();
+        expected.insert("/data/test".into(), Acc::from((7, 19456)));
+        expected.insert("/data/test/new\nline".into(), Acc::from((2, 5120)));
+        expected
+            .insert("/data/test/dashes -- here".into(), Acc::from((2, 5120)));
+        expected.insert(
+            Path::new("/data/test").join(OsStr::from_bytes(b"\xff")),
+            Acc::from((2, 5120)),
+        );
+
+        let sum = run(&["-d1"], SOURCE_ESCAPED);
+
+        assert_eq!(expected, sum);
+    }
+
+    const SOURCE_EMPTY: &str = indoc! {"
+        1 1 0  4096 2 drwxr-xr-x -- /data/test
+        2 1 0  4096 2 drwxr-xr-x -- /data/test/empty
+        3 1 0  4096 3 drwxr-xr-x -- /data/test/a
+        4 1 0  1024 1 -rw-r--r-- -- /data/test/a/foo
+        5 1 0  4096 2 drwxr-xr-x -- /data/test/a/empty
+    "};
+
+    #[test]
+    fn parse_empty_dirs() {
+        let parse = |source, args| run(args, source);
+
+        let mut expected = BTreeMap::new();
+        expected.insert("/data/test".into(), Acc::from((5, 17408)));
+        expected.insert("/data/test/a".into(), Acc::from((3, 9216)));
+        assert_eq!(expected, parse(SOURCE_EMPTY, &["-d1"]));
+
+        expected.insert("/data/test/empty".into(), Acc::from((1, 4096)));
+        assert_eq!(expected, parse(SOURCE_EMPTY, &["-d1", "--empty-dirs"]));
+
+        expected.insert("/data/test/a/empty".into(), Acc::from((1, 4096)));
+        assert_eq!(expected, parse(SOURCE_EMPTY, &["-d2", "--empty-dirs"]));
+
+        let root = SOURCE_EMPTY.lines().next().unwrap();
+        assert!(parse(root, &["-d1"]).is_empty());
+
+        let mut expected = BTreeMap::new();
+        expected.insert("/data/test".into(), Acc::from((1, 4096)));
+        assert_eq!(expected, parse(root, &["-d1", "--empty-dirs"]));
+    }
+
+    #[test]
+    fn parse_hardlinks_spilled() {
+        let expected = run(&["-d1"], SOURCE);
+
+        let sum = run(&["-d1", "--link-memory", "1"], SOURCE);
+
+        assert_eq!(expected, sum);
+    }
+
+    #[test]
+    fn parse_parallel() {
+        let report = synthetic_report(4, 20_000);
+
+        let expected = run(&["-d2", "--threads", "1"], &report);
+
+        let sum = run(&["-d2", "--threads", "4"], &report);
+
+        assert_eq!(expected, sum);
+        assert_eq!(1 + 4 + 20_000, sum[Path::new("/data/test")].inodes);
+    }
+
+    // ------------------------------------------------------------------------
+    // benchmarks: cargo test --release -- --ignored --nocapture bench
+    // ------------------------------------------------------------------------
+
+    /// Returns a synthetic report of `dirs` directories, each containing
+    /// `files` files, all of which are hard linked from every directory.
+    fn synthetic_report(dirs: u64, files: u64) -> String {
+        use std::fmt::Write;
+
+        let mut report = String::new();
+        let nlink = dirs;
+
+        writeln!(report, "1 1 0  4096 1 drwxr-xr-x -- /data/test").unwrap();
+
+        for dir in 0..dirs {
+            let inode = 2 + dir;
+            writeln!(
+                report,
+                "{inode} 1 0  4096 2 drwxr-xr-x -- /data/test/{dir}/sub"
+            )
+            .unwrap();
+
+            for file in 0..files {
+                let inode = 2 + dirs + file;
+                writeln!(
+                    report,
+                    "{inode} 1 0  1024 {nlink} -rw-r--r-- -- \
+                     /data/test/{dir}/sub/{file}"
+                )
+                .unwrap();
+            }
+        }
+
+        report
+    }
+
+    fn bench(name: &str, args: &[&str]) {
+        let report = synthetic_report(100, 10_000);
+        let start = std::time::Instant::now();
+        let sum = run(args, &report);
+        let elapsed = start.elapsed();
+
+        let lines = 1 + 100 + 100 * 10_000;
+        eprintln!("{name}: {lines} lines in {elapsed:?}");
+
+        let root = sum[Path::new("/data/test")];
+        assert_eq!(1 + 100 + 10_000, root.inodes);
+    }
+
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_hard_links_in_memory() {
+        bench("in memory", &["-d2"]);
+    }
+
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_hard_links_spilled() {
+        bench("spilled", &["-d2", "--link-memory", "1M"]);
+    }
 }
@@ -0,0 +1,725 @@
+/* * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * *
+ *                                                                           *
+ *  Copyright  (C)  2019-2024  Christian Krause                              *
+ *                                                                           *
+ *  Christian Krause  <christian.krause@idiv.de>                             *
+ *                                                                           *
+ * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * *
+ *                                                                           *
+ *  This file is part of mmdu.                                               *
+ *                                                                           *
+ *  mmdu is free software: you can redistribute it and/or modify             *
+ *  it under the terms of the GNU General Public License as published by     *
+ *  the Free Software Foundation, either version 3 of the license, or any    *
+ *  later version.                                                           *
+ *                                                                           *
+ *  mmdu is distributed in the hope that it will be useful, but              *
+ *  WITHOUT ANY WARRANTY; without even the implied warranty of               *
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU         *
+ *  General Public License for more details.                                 *
+ *                                                                           *
+ *  You should have received a copy of the GNU General Public License along  *
+ *  with mmdu. If not, see <http://www.gnu.org/licenses/>.                   *
+ *                                                                           *
+ * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * */
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::config::Config;
+use crate::policy::{FileId, Kind};
+use crate::time::Timestamp;
+use crate::usage::{Acc, Sizes, Types};
+
+/// Counts hard linked files only once with bounded memory.
+///
+/// Every occurrence of a hard linked file is recorded together with the index
+/// of the prefix, i.e. the DIR or sub-directory, it accounts for. Duplicates
+/// are removed by sorting. If the records exceed the memory limit, they are
+/// sorted and spilled to a temporary file and all of these sorted runs are
+/// merged in the end.
+pub struct HardLinks {
+    links: Vec<Link>,
+    limit: usize,
+    runs: Vec<File>,
+    spill_dir: Option<PathBuf>,
+}
+
+impl HardLinks {
+    /// Returns hard link tracker using at most `memory` bytes before spilling
+    /// to temporary files in `spill_dir`, or the system temporary directory.
+    pub fn new(memory: u64, spill_dir: Option<&Path>) -> Self {
+        let limit = usize::try_from(memory / Link::SIZE as u64)
+            .unwrap_or(usize::MAX)
+            .max(1);
+
+        Self {
+            links: Vec::new(),
+            limit,
+            runs: Vec::new(),
+            spill_dir: spill_dir.map(Path::to_path_buf),
+        }
+    }
+
+    /// Returns tracker for one of the threads parsing a report.
+    pub fn for_thread(config: &Config) -> Self {
+        let memory = share(config) / config.threads as u64;
+        Self::new(memory, config.mm_local_work_dir.as_deref())
+    }
+
+    /// Returns tracker for the hard links committed to the grand total.
+    pub fn for_total(config: &Config) -> Self {
+        Self::new(share(config), config.mm_local_work_dir.as_deref())
+    }
+
+    /// Records hard linked file `id` for the prefix with index `prefix`.
+    pub fn insert(&mut self, id: FileId, prefix: u32, acc: Acc) -> Result<()> {
+        if self.links.len() == self.limit {
+            self.spill()?;
+        }
+
+        // grow manually, the default strategy may exceed the limit
+        if self.links.len() == self.links.capacity() {
+            let additional = self
+                .links
+                .capacity()
+                .max(1024)
+                .min(self.limit - self.links.len());
+
+            self.links.reserve_exact(additional);
+        }
+
+        self.links.push(Link::new(id, prefix, acc));
+
+        Ok(())
+    }
+
+    /// Merges the hard links recorded by `other`, e.g. by another thread.
+    pub fn merge(&mut self, mut other: Self) -> Result<()> {
+        if self.links.len() + other.links.len() > self.limit {
+            other.spill()?;
+        }
+
+        self.links.append(&mut other.links);
+        self.runs.append(&mut other.runs);
+
+        Ok(())
+    }
+
+    /// Calls `f` once for every distinct file and prefix.
+    pub fn finish(
+        mut self,
+        mut f: impl FnMut(FileId, u32, Acc),
+    ) -> Result<()> {
+        if self.runs.is_empty() {
+            self.links.sort_unstable_by_key(Link::key);
+            self.links.dedup_by_key(|link| link.key());
+
+            for link in self.links {
+                f(link.id, link.prefix, link.acc());
+            }
+
+            return Ok(());
+        }
+
+        if !self.links.is_empty() {
+            self.spill()?;
+        }
+
+        mem::take(&mut self.links);
+
+        merge(self.runs, f)
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        self.links.sort_unstable_by_key(Link::key);
+        self.links.dedup_by_key(|link| link.key());
+
+        let file = self
+            .spill_dir
+            .as_ref()
+            .map_or_else(tempfile::tempfile, tempfile::tempfile_in)
+            .context("creating temporary file for hard links")?;
+
+        let mut writer = BufWriter::new(file);
+
+        for link in self.links.drain(..) {
+            link.write(&mut writer)
+                .context("writing hard links to temporary file")?;
+        }
+
+        let mut file = writer
+            .into_inner()
+            .context("writing hard links to temporary file")?;
+
+        file.rewind()
+            .context("rewinding hard links temporary file")?;
+
+        self.runs.push(file);
+
+        Ok(())
+    }
+}
+
+/// Returns the memory of each kind of tracker.
+///
+/// With a grand total, the memory is shared by the hard links of the sums,
+/// the ones pending for the grand total and the ones committed to it.
+const fn share(config: &Config) -> u64 {
+    if config.total || config.dedupe_across_dirs {
+        config.link_memory / 3
+    } else {
+        config.link_memory
+    }
+}
+
+/// Merges sorted runs, calling `f` once for every distinct file and prefix.
+fn merge(runs: Vec<File>, mut f: impl FnMut(FileId, u32, Acc)) -> Result<()> {
+    let mut runs = runs.into_iter().map(BufReader::new).collect::<Vec<_>>();
+    let mut heads = Vec::with_capacity(runs.len());
+    let mut queue = BinaryHeap::with_capacity(runs.len());
+
+    for (index, run) in runs.iter_mut().enumerate() {
+        let head = Link::read(run).context("reading hard links")?;
+
+        if let Some(link) = head {
+            queue.push(Reverse((link.key(), index)));
+        }
+
+        heads.push(head);
+    }
+
+    let mut last = None;
+
+    while let Some(Reverse((key, index))) = queue.pop() {
+        let link = heads[index].take().expect("queued run has a head");
+
+        if last != Some(key) {
+            f(link.id, link.prefix, link.acc());
+            last = Some(key);
+        }
+
+        let head =
+            Link::read(&mut runs[index]).context("reading hard links")?;
+
+        if let Some(link) = head {
+            queue.push(Reverse((link.key(), index)));
+        }
+
+        heads[index] = head;
+    }
+
+    Ok(())
+}
+
+// ----------------------------------------------------------------------------
+// files counted in previous DIRs
+// ----------------------------------------------------------------------------
+
+/// Files counted in previous DIRs with bounded memory, see
+/// `--dedupe-across-dirs`.
+///
+/// The IDs are kept sorted in memory and, beyond the memory limit, in sorted
+/// runs in temporary files. The hard links of a DIR are finished in sorted
+/// order as well, so they are checked against all of these in a single pass.
+pub struct Counted {
+    ids: Vec<FileId>,
+    limit: usize,
+    runs: Vec<File>,
+    spill_dir: Option<PathBuf>,
+}
+
+impl Counted {
+    /// Returns set using at most `memory` bytes before spilling to temporary
+    /// files in `spill_dir`, or the system temporary directory.
+    pub fn new(memory: u64, spill_dir: Option<&Path>) -> Self {
+        let limit = usize::try_from(memory / ID_SIZE as u64)
+            .unwrap_or(usize::MAX)
+            .max(1);
+
+        Self {
+            ids: Vec::new(),
+            limit,
+            runs: Vec::new(),
+            spill_dir: spill_dir.map(Path::to_path_buf),
+        }
+    }
+
+    /// Returns set with the memory of the hard links committed to the grand
+    /// total, which are counted in here instead.
+    pub fn for_total(config: &Config) -> Self {
+        Self::new(share(config), config.mm_local_work_dir.as_deref())
+    }
+
+    /// Calls `f` like [`HardLinks::finish`], except for files counted before.
+    pub fn filter(
+        &mut self,
+        links: HardLinks,
+        f: impl FnMut(FileId, u32, Acc),
+    ) -> Result<()> {
+        self.pass(links, false, f)
+    }
+
+    /// Like [`Counted::filter`], but also records the files of `links` as
+    /// counted.
+    pub fn count(
+        &mut self,
+        links: HardLinks,
+        f: impl FnMut(FileId, u32, Acc),
+    ) -> Result<()> {
+        self.pass(links, true, f)
+    }
+
+    fn pass(
+        &mut self,
+        links: HardLinks,
+        record: bool,
+        mut f: impl FnMut(FileId, u32, Acc),
+    ) -> Result<()> {
+        // the new files share the memory that is left
+        let mut added = Self {
+            ids: Vec::new(),
+            limit: self.limit.saturating_sub(self.ids.len()).max(1),
+            runs: Vec::new(),
+            spill_dir: self.spill_dir.clone(),
+        };
+
+        let mut cursor = Cursor::new(&self.ids, &self.runs)?;
+        let mut result = Ok(());
+
+        links.finish(|id, prefix, acc| {
+            if result.is_err() {
+                return;
+            }
+
+            result = cursor.contains(&id).and_then(|counted| {
+                if !counted {
+                    f(id, prefix, acc);
+
+                    if record {
+                        added.insert(id)?;
+                    }
+                }
+
+                Ok(())
+            });
+        })?;
+
+        result?;
+
+        self.append(added)
+    }
+
+    fn insert(&mut self, id: FileId) -> Result<()> {
+        if self.ids.len() == self.limit {
+            self.spill()?;
+        }
+
+        self.ids.push(id);
+
+        Ok(())
+    }
+
+    fn append(&mut self, mut other: Self) -> Result<()> {
+        if self.ids.len() + other.ids.len() > self.limit {
+            other.spill()?;
+        }
+
+        self.ids.append(&mut other.ids);
+        self.ids.sort_unstable();
+        self.ids.dedup();
+
+        self.runs.append(&mut other.runs);
+
+        Ok(())
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        self.ids.sort_unstable();
+        self.ids.dedup();
+
+        let file = self
+            .spill_dir
+            .as_ref()
+            .map_or_else(tempfile::tempfile, tempfile::tempfile_in)
+            .context("creating temporary file for counted files")?;
+
+        let mut writer = BufWriter::new(file);
+
+        for id in self.ids.drain(..) {
+            write_id(&mut writer, &id)
+                .context("writing counted files to temporary file")?;
+        }
+
+        let file = writer
+            .into_inner()
+            .context("writing counted files to temporary file")?;
+
+        self.runs.push(file);
+
+        Ok(())
+    }
+}
+
+/// Looks up files in ascending order in the sorted IDs and runs of
+/// [`Counted`].
+struct Cursor<'a> {
+    ids: &'a [FileId],
+    runs: Vec<(BufReader<&'a File>, Option<FileId>)>,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(ids: &'a [FileId], runs: &'a [File]) -> Result<Self> {
+        let runs = runs
+            .iter()
+            .map(|mut run| {
+                run.rewind()?;
+                let mut reader = BufReader::new(run);
+                let head = read_id(&mut reader)?;
+                Ok((reader, head))
+            })
+            .collect::<io::Result<_>>()
+            .context("reading counted files")?;
+
+        Ok(Self { ids, runs })
+    }
+
+    /// Returns `true` if `id` was counted, `id` must not decrease between
+    /// calls.
+    fn contains(&mut self, id: &FileId) -> Result<bool> {
+        let skip = self.ids.partition_point(|counted| counted < id);
+        self.ids = &self.ids[skip..];
+
+        let mut found = self.ids.first() == Some(id);
+
+        for (reader, head) in &mut self.runs {
+            while head.as_ref().is_some_and(|head| head < id) {
+                *head = read_id(reader).context("reading counted files")?;
+            }
+
+            found |= head.as_ref() == Some(id);
+        }
+
+        Ok(found)
+    }
+}
+
+// ----------------------------------------------------------------------------
+// record
+// ----------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Debug)]
+struct Link {
+    id: FileId,
+    prefix: u32,
+    bytes: u64,
+    time: Option<Timestamp>,
+    kind: Option<Kind>,
+    sizes: Sizes,
+}
+
+impl Link {
+    /// Size of the on-disk record, also used to estimate memory.
+    const SIZE: usize = ID_SIZE + 4 + 8 + 8 + 1 + 8 * 2;
+
+    /// Marks absence of time in the on-disk record.
+    const NO_TIME: i64 = i64::MIN;
+
+    const fn new(id: FileId, prefix: u32, acc: Acc) -> Self {
+        Self {
+            id,
+            prefix,
+            bytes: acc.bytes,
+            time: acc.time,
+            kind: acc.types.kind(),
+            sizes: acc.sizes,
+        }
+    }
+
+    const fn key(&self) -> (FileId, u32) {
+        (self.id, self.prefix)
+    }
+
+    const fn acc(&self) -> Acc {
+        let mut acc = Acc::new(self.bytes, self.time);
+
+        if let Some(kind) = self.kind {
+            acc.types = Types::new(kind);
+        }
+
+        acc.sizes = self.sizes;
+
+        acc
+    }
+
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let time = self.time.map_or(Self::NO_TIME, Timestamp::seconds);
+
+        write_id(writer, &self.id)?;
+        writer.write_all(&self.prefix.to_le_bytes())?;
+        writer.write_all(&self.bytes.to_le_bytes())?;
+        writer.write_all(&time.to_le_bytes())?;
+        writer.write_all(&[kind_to_byte(self.kind)])?;
+        writer.write_all(&self.sizes.apparent.to_le_bytes())?;
+        writer.write_all(&self.sizes.allocated.to_le_bytes())
+    }
+
+    fn read(reader: &mut impl Read) -> io::Result<Option<Self>> {
+        let mut buf = [0; Self::SIZE];
+
+        match reader.read_exact(&mut buf) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        }
+
+        let (id, buf) = id_le(&buf);
+        let (prefix, buf) = buf.split_first_chunk::<4>().unwrap();
+        let (bytes, buf) = u64_le(buf);
+        let (time, buf) = buf.split_first_chunk::<8>().unwrap();
+        let time = i64::from_le_bytes(*time);
+        let (kind, buf) = buf.split_first().unwrap();
+        let kind = kind_from_byte(*kind);
+        let (apparent, buf) = u64_le(buf);
+        let (allocated, _) = u64_le(buf);
+
+        Ok(Some(Self {
+            id,
+            prefix: u32::from_le_bytes(*prefix),
+            bytes,
+            time: (time != Self::NO_TIME)
+                .then(|| Timestamp::from_seconds(time)),
+            kind,
+            sizes: Sizes {
+                apparent,
+                allocated,
+            },
+        }))
+    }
+}
+
+/// Encodes the kind in the on-disk record, zero if types are not counted.
+const fn kind_to_byte(kind: Option<Kind>) -> u8 {
+    match kind {
+        None => 0,
+        Some(Kind::File) => 1,
+        Some(Kind::Dir) => 2,
+        Some(Kind::Symlink) => 3,
+        Some(Kind::Other) => 4,
+    }
+}
+
+const fn kind_from_byte(kind: u8) -> Option<Kind> {
+    match kind {
+        1 => Some(Kind::File),
+        2 => Some(Kind::Dir),
+        3 => Some(Kind::Symlink),
+        4 => Some(Kind::Other),
+        _ => None,
+    }
+}
+
+const fn u64_le(buf: &[u8]) -> (u64, &[u8]) {
+    let (value, rest) = buf.split_first_chunk::<8>().unwrap();
+    (u64::from_le_bytes(*value), rest)
+}
+
+/// Size of an on-disk file ID.
+const ID_SIZE: usize = 8 * 4;
+
+fn write_id(writer: &mut impl Write, id: &FileId) -> io::Result<()> {
+    writer.write_all(&id.device.to_le_bytes())?;
+    writer.write_all(&id.inode.to_le_bytes())?;
+    writer.write_all(&id.generation.to_le_bytes())?;
+    writer.write_all(&id.snapshot.to_le_bytes())
+}
+
+fn read_id(reader: &mut impl Read) -> io::Result<Option<FileId>> {
+    let mut buf = [0; ID_SIZE];
+
+    match reader.read_exact(&mut buf) {
+        Ok(()) => Ok(Some(id_le(&buf).0)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+const fn id_le(buf: &[u8]) -> (FileId, &[u8]) {
+    let (device, buf) = u64_le(buf);
+    let (inode, buf) = u64_le(buf);
+    let (generation, buf) = u64_le(buf);
+    let (snapshot, buf) = u64_le(buf);
+
+    let id = FileId {
+        device,
+        inode,
+        generation,
+        snapshot,
+    };
+
+    (id, buf)
+}
+
+// ----------------------------------------------------------------------------
+// tests
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn id(inode: u64) -> FileId {
+        FileId {
+            device: 0,
+            inode,
+            generation: 1,
+            snapshot: 0,
+        }
+    }
+
+    fn collect(links: HardLinks) -> Vec<(u32, Acc)> {
+        let mut result = vec![];
+        links
+            .finish(|_, prefix, acc| result.push((prefix, acc)))
+            .unwrap();
+        result.sort_unstable_by_key(|(prefix, acc)| (*prefix, acc.bytes));
+        result
+    }
+
+    fn insert_all(links: &mut HardLinks) {
+        for (inode, prefix, bytes) in [
+            (1, 0, 10),
+            (2, 0, 20),
+            (1, 0, 10),
+            (1, 1, 10),
+            (3, 1, 30),
+            (2, 0, 20),
+            (1, 1, 10),
+        ] {
+            let acc = Acc::new(bytes, Some(Timestamp::from_seconds(-1)));
+            links.insert(id(inode), prefix, acc).unwrap();
+        }
+    }
+
+    fn expected() -> Vec<(u32, Acc)> {
+        let time = Some(Timestamp::from_seconds(-1));
+
+        vec![
+            (0, Acc::new(10, time)),
+            (0, Acc::new(20, time)),
+            (1, Acc::new(10, time)),
+            (1, Acc::new(30, time)),
+        ]
+    }
+
+    #[test]
+    fn in_memory() {
+        let mut links = HardLinks::new(1 << 20, None);
+        insert_all(&mut links);
+
+        assert!(links.runs.is_empty());
+        assert_eq!(expected(), collect(links));
+    }
+
+    #[test]
+    fn spilled() {
+        let mut links = HardLinks::new(2 * Link::SIZE as u64, None);
+        insert_all(&mut links);
+
+        assert_eq!(3, links.runs.len());
+        assert_eq!(expected(), collect(links));
+    }
+
+    #[test]
+    fn merged() {
+        let mut links = HardLinks::new(1 << 20, None);
+        insert_all(&mut links);
+
+        let mut spilled = HardLinks::new(2 * Link::SIZE as u64, None);
+        insert_all(&mut spilled);
+
+        let mut other = HardLinks::new(1 << 20, None);
+        insert_all(&mut other);
+
+        links.merge(spilled).unwrap();
+        links.merge(other).unwrap();
+
+        assert_eq!(expected(), collect(links));
+    }
+
+    fn count(counted: &mut Counted, inodes: &[u64]) -> Vec<u64> {
+        let mut links = HardLinks::new(1 << 20, None);
+
+        for inode in inodes {
+            links.insert(id(*inode), 0, Acc::default()).unwrap();
+        }
+
+        let mut result = vec![];
+        counted
+            .count(links, |id, _, _| result.push(id.inode))
+            .unwrap();
+        result
+    }
+
+    #[test]
+    fn counted() {
+        for memory in [1 << 20, 2 * ID_SIZE as u64] {
+            let mut counted = Counted::new(memory, None);
+
+            assert_eq!(vec![1, 3, 5], count(&mut counted, &[5, 3, 1, 3]));
+            assert_eq!(vec![2, 4, 6], count(&mut counted, &[1, 2, 4, 6, 5]));
+            assert_eq!(vec![7], count(&mut counted, &[6, 4, 7, 2, 3]));
+            assert!(count(&mut counted, &[1, 2, 3, 4, 5, 6, 7]).is_empty());
+
+            let mut links = HardLinks::new(1 << 20, None);
+            for (inode, prefix) in [(8, 1), (2, 0), (8, 0), (2, 1)] {
+                links.insert(id(inode), prefix, Acc::default()).unwrap();
+            }
+
+            let mut result = vec![];
+            counted
+                .filter(links, |id, prefix, _| result.push((id.inode, prefix)))
+                .unwrap();
+            assert_eq!(vec![(8, 0), (8, 1)], result);
+
+            // filtering does not record
+            assert_eq!(vec![8], count(&mut counted, &[8]));
+
+            if memory < 1 << 20 {
+                assert!(!counted.runs.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn record() {
+        let mut with_kind = Acc::new(1024, None);
+        with_kind.types = Types::new(Kind::Symlink);
+        with_kind.sizes = Sizes {
+            apparent: 1024,
+            allocated: 8,
+        };
+
+        for acc in [Acc::new(1024, None), with_kind] {
+            let link = Link::new(id(42), 7, acc);
+
+            let mut buf = vec![];
+            link.write(&mut buf).unwrap();
+            assert_eq!(Link::SIZE, buf.len());
+
+            let read = Link::read(&mut buf.as_slice()).unwrap().unwrap();
+            assert_eq!(link.key(), read.key());
+            assert_eq!(acc, read.acc());
+        }
+
+        assert!(Link::read(&mut [].as_slice()).unwrap().is_none());
+    }
+}
@@ -24,21 +24,70 @@
  * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * */
 
 mod depth;
+mod links;
+mod ncdu;
+mod report;
 mod total;
+mod tree;
 
-use std::fs::File;
+pub use self::tree::Tree;
+
+use std::cmp::Reverse;
+use std::fs::{self, File};
 use std::ops::AddAssign;
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use std::process::{Command, Stdio};
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::{Context, Result, anyhow};
 use clap::crate_name;
+use libc::uid_t;
 use tempfile::{tempdir, tempdir_in};
 
-use crate::config::Config;
-use crate::output::output;
+use crate::config::{ByteMode, Config, CountMode, Sort};
+use crate::output::{Output, Record};
+use crate::policy::{Entry, FileId, Kind};
+use crate::time::Timestamp;
+use crate::usage::links::{Counted, HardLinks};
+use crate::usage::report::Saved;
+
+/// Metadata of the `mmapplypolicy` scan of a DIR.
+#[derive(Clone, Copy, Debug)]
+pub struct Scan {
+    pub started: SystemTime,
+    pub duration: Duration,
+}
+
+pub fn run(
+    dir: &Path,
+    config: &Config,
+    total: Option<&mut GrandTotal>,
+    first: bool,
+    output: &mut Output,
+) -> Result<()> {
+    let only_first = [
+        (&config.export_ncdu, "exporting", "exported"),
+        (&config.save_report, "saving", "saved"),
+    ];
+
+    for (file, action, done) in only_first {
+        if let Some(file) = file.as_deref().filter(|_| !first) {
+            let (dir, file) = (dir.display(), file.display());
+
+            #[cfg(not(feature = "log"))]
+            eprintln!(
+                "{}: not {action} {dir}: only the first DIR is {done} to {file}",
+                crate_name!()
+            );
+
+            #[cfg(feature = "log")]
+            log::warn!(
+                "not {action} {dir}: only the first DIR is {done} to {file}"
+            );
+        }
+    }
 
-pub fn run(dir: &Path, config: &Config) -> Result<()> {
     let tmp = if let Some(ref local_work_dir) = config.mm_local_work_dir {
         tempdir_in(local_work_dir).with_context(|| {
             format!("creating tempdir in {}", local_work_dir.display())
@@ -78,6 +127,9 @@ pub fn run(dir: &Path, config: &Config) -> Result<()> {
     #[cfg(feature = "log")]
     log::debug!("command: {command:?}");
 
+    let started = SystemTime::now();
+    let start = Instant::now();
+
     let mut child = command
         .stdout(Stdio::null())
         .spawn()
@@ -85,10 +137,23 @@ pub fn run(dir: &Path, config: &Config) -> Result<()> {
 
     let ecode = child.wait().context("failed waiting on `mmapplypolicy`")?;
 
+    let scan = Scan {
+        started,
+        duration: start.elapsed(),
+    };
+
     if ecode.success() {
         let report = tmp.path().join("mmdu.list.size");
 
-        sum(dir, &report, config)?;
+        if let Some(file) = config.save_report.as_deref().filter(|_| first) {
+            report::save(&report, file, dir, config).with_context(|| {
+                format!("saving report to {}", file.display())
+            })?;
+        }
+
+        let export = config.export_ncdu.as_deref().filter(|_| first);
+
+        sum(dir, &report, config, total, export, &scan, output)?;
 
         Ok(())
     } else {
@@ -104,7 +169,19 @@ pub fn run(dir: &Path, config: &Config) -> Result<()> {
     }
 }
 
-fn sum(dir: &Path, report: &Path, config: &Config) -> Result<()> {
+fn sum(
+    dir: &Path,
+    report: &Path,
+    config: &Config,
+    mut total: Option<&mut GrandTotal>,
+    export: Option<&Path>,
+    scan: &Scan,
+    output: &mut Output,
+) -> Result<()> {
+    let device = fs::metadata(dir)
+        .with_context(|| format!("reading metadata of {}", dir.display()))?
+        .dev();
+
     let report = File::open(report).with_context(|| {
         format!(
             "opening report {} (this is likely because applying a \
@@ -113,48 +190,522 @@ fn sum(dir: &Path, report: &Path, config: &Config) -> Result<()> {
         )
     })?;
 
-    if let Some(depth) = config.max_depth {
-        let sizes = depth::sum(dir, depth, report, config.count_links)?;
+    // the export needs the full tree, the output is taken from it as well
+    let full = match export {
+        Some(_) => Some(depth::tree(
+            dir,
+            MAX_DEPTH,
+            &report,
+            device,
+            config,
+            total.take(),
+            true,
+        )?),
+        None => None,
+    };
+
+    let skipped = if let Some(depth) = config.max_depth {
+        let (sizes, skipped) = match &full {
+            Some((tree, skipped)) => {
+                (depth::select(tree, depth, config, *skipped), *skipped)
+            }
+            None => depth::sum(dir, depth, &report, device, config, total)?,
+        };
+
+        let mut entries = sizes
+            .iter()
+            .filter(|(_, acc)| acc.exceeds(config))
+            .map(|(path, acc)| (path.as_path(), *acc))
+            .collect::<Vec<_>>();
+
+        sort(&mut entries, config);
+
+        let limit = config.limit.unwrap_or(usize::MAX);
 
-        for (dir, Acc { inodes, bytes }) in sizes {
-            output(&dir, inodes, bytes, config);
+        for (path, acc) in entries.into_iter().take(limit) {
+            // the parent directory has more than one inode, so it is present
+            let parent = path
+                .parent()
+                .filter(|_| path != dir)
+                .and_then(|parent| sizes.get(parent));
+
+            output.record(&Record {
+                path,
+                acc: &acc,
+                dir: Some(dir),
+                scan: Some(scan),
+                parent,
+                top: sizes.get(dir),
+            });
         }
+
+        skipped
     } else {
-        let Acc { inodes, bytes } = total::sum(report, config.count_links)?;
-        output(dir, inodes, bytes, config);
+        let acc = match &full {
+            Some((tree, skipped)) => Acc {
+                skipped: *skipped,
+                ..tree[Tree::ROOT].acc
+            },
+            None => total::sum(dir, &report, device, config, total)?,
+        };
+
+        if acc.exceeds(config) {
+            output.record(&Record {
+                path: dir,
+                acc: &acc,
+                dir: Some(dir),
+                scan: Some(scan),
+                parent: None,
+                top: Some(&acc),
+            });
+        }
+
+        acc.skipped
+    };
+
+    if skipped > 0 {
+        approximate(dir, skipped);
+    }
+
+    if let Some((file, (tree, _))) = export.zip(full) {
+        ncdu::export(&tree, device, scan, file)
+            .with_context(|| format!("exporting to {}", file.display()))?;
     }
 
     Ok(())
 }
 
+/// Sorts entries in path order by `--sort` and `--reverse`.
+fn sort<P>(entries: &mut [(P, Acc)], config: &Config) {
+    // the sorts are stable, ties remain in path order
+    match config.sort {
+        Sort::Bytes => entries.sort_by_key(|(_, acc)| Reverse(acc.bytes)),
+        Sort::Inodes => entries.sort_by_key(|(_, acc)| Reverse(acc.inodes)),
+        Sort::Time => entries.sort_by_key(|(_, acc)| Reverse(acc.time)),
+        Sort::Name => {}
+    }
+
+    if config.reverse {
+        entries.reverse();
+    }
+}
+
+/// A path has at most `PATH_MAX / 2` components.
+const MAX_DEPTH: usize = 2048;
+
+/// Notes that the output of `dir` is approximate, see `--on-parse-error`.
+fn approximate(dir: &Path, skipped: u64) {
+    let dir = dir.display();
+
+    #[cfg(not(feature = "log"))]
+    eprintln!(
+        "{}: {dir}: skipped {skipped} invalid lines, the output is approximate",
+        crate_name!()
+    );
+
+    #[cfg(feature = "log")]
+    log::warn!(
+        "{dir}: skipped {skipped} invalid lines, the output is approximate"
+    );
+}
+
+/// Loads a report saved with `--save-report`.
+///
+/// Returns the tree of the DIR the report was saved for, along with `config`
+/// changed to the columns of the report.
+pub fn load(report: &Path, config: &Config) -> Result<(Tree, Config)> {
+    let (report, config) = Saved::open(report, config)?;
+
+    let (tree, _) = depth::tree(
+        report.dir(),
+        MAX_DEPTH,
+        &report,
+        0,
+        &config,
+        None,
+        false,
+    )?;
+
+    Ok((tree, config))
+}
+
 // ----------------------------------------------------------------------------
 // accumulator
 // ----------------------------------------------------------------------------
 
-#[derive(Clone, Copy, Debug, Default, PartialEq)]
-struct Acc {
-    inodes: u64,
-    bytes: u64,
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Acc {
+    pub inodes: u64,
+    pub bytes: u64,
+
+    /// The newest time, only present with `--time`.
+    pub time: Option<Timestamp>,
+
+    /// Number of invalid lines skipped, the sums are approximate if any.
+    pub skipped: u64,
+
+    /// The inodes by type, only counted with `--types`.
+    pub types: Types,
+
+    /// Apparent and allocated size, only counted with `--allocation`.
+    pub sizes: Sizes,
+
+    /// The user ID of the entry itself, not of its contents, only present
+    /// with `--columns owner`.
+    pub owner: Option<uid_t>,
 }
 
 impl Acc {
-    const fn new(bytes: u64) -> Self {
-        Self { inodes: 1, bytes }
+    const fn new(bytes: u64, time: Option<Timestamp>) -> Self {
+        Self {
+            inodes: 1,
+            bytes,
+            time,
+            skipped: 0,
+            types: Types::NONE,
+            sizes: Sizes::NONE,
+            owner: None,
+        }
+    }
+
+    /// Returns the sums of a single entry.
+    const fn from_entry(entry: &Entry, config: &Config) -> Self {
+        let mut acc = Self::new(entry.bytes, entry.time);
+
+        if config.types {
+            acc.types = Types::new(entry.kind());
+        }
+
+        if let Some(other_bytes) = entry.other_bytes {
+            acc.sizes = match config.byte_mode {
+                ByteMode::FileSize => Sizes {
+                    apparent: entry.bytes,
+                    allocated: other_bytes,
+                },
+                ByteMode::KBAllocated => Sizes {
+                    apparent: other_bytes,
+                    allocated: entry.bytes,
+                },
+            };
+        }
+
+        acc
+    }
+
+    /// Returns `true` if this passes the `--threshold` of the config.
+    ///
+    /// With `--both`, only the bytes are compared to the threshold.
+    const fn exceeds(&self, config: &Config) -> bool {
+        let Some(threshold) = config.threshold else {
+            return true;
+        };
+
+        let size = match (config.count_mode, config.byte_mode) {
+            (CountMode::Inodes, _) => self.inodes,
+            (_, ByteMode::FileSize) => self.bytes,
+            (_, ByteMode::KBAllocated) => self.bytes.saturating_mul(1024),
+        };
+
+        threshold.includes(size)
     }
 }
 
-impl AddAssign<u64> for Acc {
-    fn add_assign(&mut self, bytes: u64) {
+impl AddAssign for Acc {
+    fn add_assign(&mut self, other: Self) {
         *self = Self {
-            inodes: self.inodes + 1,
-            bytes: self.bytes + bytes,
+            inodes: self.inodes + other.inodes,
+            bytes: self.bytes + other.bytes,
+            time: self.time.max(other.time),
+            skipped: self.skipped + other.skipped,
+            types: Types {
+                files: self.types.files + other.types.files,
+                dirs: self.types.dirs + other.types.dirs,
+                symlinks: self.types.symlinks + other.types.symlinks,
+                other: self.types.other + other.types.other,
+            },
+            sizes: Sizes {
+                apparent: self.sizes.apparent + other.sizes.apparent,
+                allocated: self.sizes.allocated + other.sizes.allocated,
+            },
+            owner: self.owner.or(other.owner),
         };
     }
 }
 
+/// Apparent and allocated size.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Sizes {
+    /// The apparent size in bytes, i.e. `FILE_SIZE`.
+    pub apparent: u64,
+
+    /// The allocated size in KiB, i.e. `KB_ALLOCATED`.
+    pub allocated: u64,
+}
+
+impl Sizes {
+    const NONE: Self = Self {
+        apparent: 0,
+        allocated: 0,
+    };
+
+    /// Returns allocated size divided by apparent size.
+    pub fn ratio(&self) -> Option<f64> {
+        // ALLOW precision loss is irrelevant for a ratio
+        #[allow(clippy::cast_precision_loss)]
+        let ratio = (self.allocated as f64 * 1024.0) / self.apparent as f64;
+
+        (self.apparent > 0).then_some(ratio)
+    }
+}
+
+/// Number of inodes by type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Types {
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    pub other: u64,
+}
+
+impl Types {
+    const NONE: Self = Self {
+        files: 0,
+        dirs: 0,
+        symlinks: 0,
+        other: 0,
+    };
+
+    /// Returns the types of a single inode.
+    const fn new(kind: Kind) -> Self {
+        match kind {
+            Kind::File => Self {
+                files: 1,
+                ..Self::NONE
+            },
+            Kind::Dir => Self {
+                dirs: 1,
+                ..Self::NONE
+            },
+            Kind::Symlink => Self {
+                symlinks: 1,
+                ..Self::NONE
+            },
+            Kind::Other => Self {
+                other: 1,
+                ..Self::NONE
+            },
+        }
+    }
+
+    /// Returns the kind of a single inode, if its type was counted.
+    const fn kind(&self) -> Option<Kind> {
+        match (self.files, self.dirs, self.symlinks, self.other) {
+            (1, 0, 0, 0) => Some(Kind::File),
+            (0, 1, 0, 0) => Some(Kind::Dir),
+            (0, 0, 1, 0) => Some(Kind::Symlink),
+            (0, 0, 0, 1) => Some(Kind::Other),
+            _ => None,
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+// grand total
+// ----------------------------------------------------------------------------
+
+/// Grand total of all DIRs, see `--total`.
+///
+/// Hard links are tracked across all DIRs, so they are counted only once. The
+/// entries of each DIR are pending until they are either committed when the
+/// DIR succeeded or discarded when it was skipped. With
+/// `--dedupe-across-dirs`, the committed hard links are not counted again in
+/// the following DIRs.
+pub struct GrandTotal {
+    acc: Acc,
+    hard_links: HardLinks,
+    counted: Option<Counted>,
+    pending: Option<DirTotal>,
+}
+
+impl GrandTotal {
+    pub fn new(config: &Config) -> Self {
+        // with --dedupe-across-dirs, hard links are committed to `counted`
+        let counted = config
+            .dedupe_across_dirs
+            .then(|| Counted::for_total(config));
+
+        Self {
+            acc: Acc::default(),
+            hard_links: HardLinks::for_total(config),
+            counted,
+            pending: None,
+        }
+    }
+
+    fn add_pending(&mut self, pending: DirTotal) -> Result<()> {
+        if let Some(total) = &mut self.pending {
+            total.merge(pending)
+        } else {
+            self.pending = Some(pending);
+            Ok(())
+        }
+    }
+
+    pub fn commit(&mut self) -> Result<()> {
+        let Some(pending) = self.pending.take() else {
+            return Ok(());
+        };
+
+        self.acc += pending.acc;
+
+        let Some(counted) = &mut self.counted else {
+            return self.hard_links.merge(pending.hard_links);
+        };
+
+        counted.count(pending.hard_links, |_, _, acc| self.acc += acc)
+    }
+
+    /// Calls `f` like [`HardLinks::finish`], except for the files counted in
+    /// previous DIRs, see `--dedupe-across-dirs`.
+    fn finish_links(
+        total: Option<&mut Self>,
+        links: HardLinks,
+        f: impl FnMut(FileId, u32, Acc),
+    ) -> Result<()> {
+        match total.and_then(|total| total.counted.as_mut()) {
+            Some(counted) => counted.filter(links, f),
+            None => links.finish(f),
+        }
+    }
+
+    pub fn discard(&mut self) {
+        self.pending = None;
+    }
+
+    /// Returns the grand total, counting every hard linked file once.
+    pub fn finish(self) -> Result<Acc> {
+        let mut acc = self.acc;
+        self.hard_links.finish(|_, _, link| acc += link)?;
+        Ok(acc)
+    }
+}
+
+/// Contribution of (a part of) a DIR to the grand total.
+struct DirTotal {
+    acc: Acc,
+    hard_links: HardLinks,
+}
+
+impl DirTotal {
+    fn new(config: &Config) -> Self {
+        Self {
+            acc: Acc::default(),
+            hard_links: HardLinks::for_thread(config),
+        }
+    }
+
+    fn add(
+        &mut self,
+        entry: &Entry,
+        id: FileId,
+        acc: Acc,
+        count_links: bool,
+    ) -> Result<()> {
+        if count_links || !entry.is_hard_link() {
+            self.acc += acc;
+            Ok(())
+        } else {
+            self.hard_links.insert(id, 0, acc)
+        }
+    }
+
+    fn merge(&mut self, other: Self) -> Result<()> {
+        self.acc += other.acc;
+        self.hard_links.merge(other.hard_links)
+    }
+}
+
 #[cfg(test)]
 impl From<(u64, u64)> for Acc {
     fn from((inodes, bytes): (u64, u64)) -> Self {
-        Self { inodes, bytes }
+        Self {
+            inodes,
+            bytes,
+            time: None,
+            skipped: 0,
+            types: Types::NONE,
+            sizes: Sizes::NONE,
+            owner: None,
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+// tests
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use indoc::indoc;
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    #[test]
+    fn load_dir() {
+        let config = Config::from_args(&[]);
+
+        // everything in one subdirectory, e.g. with --user
+        let source = indoc! {"
+            # mmdu report: FILE_SIZE MODIFICATION_TIME -- /data/test
+            2 1 0  1024 1 -rw-r--r-- 2024-02-01 00:00:00 -- /data/test/a/foo
+            4 1 0  1024 1 -rw-r--r-- 2024-02-01 00:00:00 -- /data/test/a/bar
+        "};
+
+        let mut report = NamedTempFile::new().unwrap();
+        report.write_all(source.as_bytes()).unwrap();
+
+        let (tree, _) = load(report.path(), &config).unwrap();
+        assert_eq!(Path::new("/data/test"), tree.path(Tree::ROOT));
+        assert_eq!(2, tree[Tree::ROOT].acc.inodes);
+        assert_eq!(2048, tree[Tree::ROOT].acc.bytes);
+    }
+
+    #[test]
+    fn sort_entries() {
+        let entries = [("a", 1, 4096), ("b", 3, 1024), ("c", 2, 4096)].map(
+            |(path, inodes, bytes)| {
+                (PathBuf::from(path), Acc::from((inodes, bytes)))
+            },
+        );
+
+        let sorted = |args: &[&str]| {
+            let mut entries = entries.clone();
+            sort(&mut entries, &Config::from_args(args));
+            entries.map(|(path, _)| path.display().to_string())
+        };
+
+        assert_eq!(["a", "b", "c"], sorted(&["-d1"]));
+        assert_eq!(["c", "b", "a"], sorted(&["-d1", "--reverse"]));
+        assert_eq!(["a", "c", "b"], sorted(&["-d1", "--sort=size"]));
+        assert_eq!(["b", "c", "a"], sorted(&["-d1", "--sort=inodes"]));
+        assert_eq!(["a", "b", "c"], sorted(&["-d1", "--sort=name"]));
+        assert_eq!(["b", "c", "a"], sorted(&["-d1", "--sort=size", "-r"]));
+    }
+
+    #[test]
+    fn sizes_ratio() {
+        let sizes = |apparent, allocated| Sizes {
+            apparent,
+            allocated,
+        };
+
+        assert_eq!(None, sizes(0, 0).ratio());
+        assert_eq!(Some(1.0), sizes(4096, 4).ratio());
+        assert_eq!(Some(0.5), sizes(2048, 1).ratio());
+        assert_eq!(Some(0.0), sizes(1 << 20, 0).ratio());
     }
 }
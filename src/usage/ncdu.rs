@@ -0,0 +1,299 @@
+/* * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * *
+ *                                                                           *
+ *  Copyright  (C)  2019-2024  Christian Krause                              *
+ *                                                                           *
+ *  Christian Krause  <christian.krause@idiv.de>                             *
+ *                                                                           *
+ * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * *
+ *                                                                           *
+ *  This file is part of mmdu.                                               *
+ *                                                                           *
+ *  mmdu is free software: you can redistribute it and/or modify             *
+ *  it under the terms of the GNU General Public License as published by     *
+ *  the Free Software Foundation, either version 3 of the license, or any    *
+ *  later version.                                                           *
+ *                                                                           *
+ *  mmdu is distributed in the hope that it will be useful, but              *
+ *  WITHOUT ANY WARRANTY; without even the implied warranty of               *
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU         *
+ *  General Public License for more details.                                 *
+ *                                                                           *
+ *  You should have received a copy of the GNU General Public License along  *
+ *  with mmdu. If not, see <http://www.gnu.org/licenses/>.                   *
+ *                                                                           *
+ * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * */
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use anyhow::{Context, Result};
+use clap::{crate_name, crate_version};
+use serde::Serialize;
+
+use crate::policy::Kind;
+use crate::usage::Scan;
+use crate::usage::tree::Tree;
+
+/// Major and minor version of the export format.
+const VERSION: (u32, u32) = (1, 2);
+
+/// Writes the full tree of a DIR to `file` in the JSON export format of ncdu.
+///
+/// The tree needs the attributes of the entries, see [`depth::tree`]. Its
+/// directories without an entry, e.g. with `--user`, have no attributes. See
+/// <https://dev.yorhel.nl/ncdu/jsonfmt> for the format.
+///
+/// [`depth::tree`]: crate::usage::depth::tree
+pub fn export(
+    tree: &Tree,
+    device: u64,
+    scan: &Scan,
+    file: &Path,
+) -> Result<()> {
+    let file = File::create(file)
+        .with_context(|| format!("creating {}", file.display()))?;
+
+    let mut writer = BufWriter::new(file);
+
+    write(&mut writer, tree, device, scan)?;
+
+    writer.flush().context("writing ncdu export")
+}
+
+fn write<W: Write>(
+    writer: &mut W,
+    tree: &Tree,
+    device: u64,
+    scan: &Scan,
+) -> Result<()> {
+    let timestamp = scan
+        .started
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |started| started.as_secs());
+
+    let metadata = JsonMetadata {
+        progname: crate_name!(),
+        progver: crate_version!(),
+        timestamp,
+    };
+
+    let (major, minor) = VERSION;
+    write!(writer, "[{major},{minor},")?;
+    serde_json::to_writer(&mut *writer, &metadata)?;
+    writer.write_all(b",")?;
+    write_node(writer, tree, Tree::ROOT, Some(device))?;
+    writer.write_all(b"]\n")?;
+
+    Ok(())
+}
+
+/// Writes a file as an object, or a directory as an array of its own object
+/// followed by its children.
+fn write_node<W: Write>(
+    writer: &mut W,
+    tree: &Tree,
+    node: u32,
+    device: Option<u64>,
+) -> Result<()> {
+    let item = tree[node].item;
+    let mut children = tree.children(node).peekable();
+
+    let dir = node == Tree::ROOT
+        || children.peek().is_some()
+        || item.is_none_or(|item| item.kind == Kind::Dir);
+
+    let hard_link = !dir && item.is_some_and(|item| item.nlink > 1);
+
+    let json = JsonItem {
+        name: tree[node].name().to_string_lossy(),
+        asize: item.map(|item| item.sizes.apparent),
+        dsize: item.map(|item| item.sizes.allocated.saturating_mul(1024)),
+        dev: device,
+        ino: item.map(|item| item.inode),
+        hlnkc: hard_link,
+        nlink: item.map(|item| item.nlink).filter(|_| hard_link),
+        notreg: item.is_some_and(|item| {
+            matches!(item.kind, Kind::Symlink | Kind::Other)
+        }),
+    };
+
+    if dir {
+        writer.write_all(b"[")?;
+        serde_json::to_writer(&mut *writer, &json)?;
+
+        for child in children {
+            writer.write_all(b",")?;
+            write_node(writer, tree, child, None)?;
+        }
+
+        writer.write_all(b"]")?;
+    } else {
+        serde_json::to_writer(&mut *writer, &json)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct JsonMetadata {
+    progname: &'static str,
+    progver: &'static str,
+    timestamp: u64,
+}
+
+#[derive(Serialize)]
+struct JsonItem<'a> {
+    name: Cow<'a, str>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    asize: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dsize: Option<u64>,
+
+    /// Only for the root, ncdu inherits it from the parent otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dev: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ino: Option<u64>,
+
+    #[serde(skip_serializing_if = "is_false")]
+    hlnkc: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nlink: Option<u64>,
+
+    #[serde(skip_serializing_if = "is_false")]
+    notreg: bool,
+}
+
+// ALLOW signature is required by serde
+#[allow(clippy::trivially_copy_pass_by_ref)]
+const fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+// ----------------------------------------------------------------------------
+// tests
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use indoc::indoc;
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::config::Config;
+    use crate::usage::{MAX_DEPTH, depth};
+
+    const SOURCE: &str = indoc! {"
+        1 1 0  4096 3 drwxr-xr-x 4 -- /data/test
+        2 1 0  1024 1 -rw-r--r-- 4 -- /data/test/foo
+        3 1 0  4096 2 drwxr-xr-x 4 -- /data/test/a
+        4 1 0  100 2 -rw-r--r-- 4 -- /data/test/a/b/link
+        4 1 0  100 2 -rw-r--r-- 4 -- /data/test/a/c
+        5 1 0  7 1 lrwxrwxrwx 0 -- /data/test/a/d
+    "};
+
+    fn export(source: &str, config: &Config) -> Value {
+        let dir = Path::new("/data/test");
+
+        let scan = Scan {
+            started: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            duration: Duration::ZERO,
+        };
+
+        let source = source.as_bytes();
+        let (tree, _) =
+            depth::tree(dir, MAX_DEPTH, source, 0, config, None, true)
+                .unwrap();
+
+        let mut json = Vec::new();
+        write(&mut json, &tree, 42, &scan).unwrap();
+
+        serde_json::from_slice(&json).expect("valid JSON")
+    }
+
+    #[test]
+    fn full_tree() {
+        let config = Config::from_args(&["--export-ncdu", "mmdu.json"]);
+
+        let expected = json!([
+            1,
+            2,
+            {
+                "progname": "mmdu",
+                "progver": crate_version!(),
+                "timestamp": 1_700_000_000,
+            },
+            [
+                {
+                    "name": "/data/test",
+                    "asize": 4096,
+                    "dsize": 4096,
+                    "dev": 42,
+                    "ino": 1,
+                },
+                [
+                    { "name": "a", "asize": 4096, "dsize": 4096, "ino": 3 },
+                    [
+                        { "name": "b" },
+                        {
+                            "name": "link",
+                            "asize": 100,
+                            "dsize": 4096,
+                            "ino": 4,
+                            "hlnkc": true,
+                            "nlink": 2,
+                        },
+                    ],
+                    {
+                        "name": "c",
+                        "asize": 100,
+                        "dsize": 4096,
+                        "ino": 4,
+                        "hlnkc": true,
+                        "nlink": 2,
+                    },
+                    {
+                        "name": "d",
+                        "asize": 7,
+                        "dsize": 0,
+                        "ino": 5,
+                        "notreg": true,
+                    },
+                ],
+                { "name": "foo", "asize": 1024, "dsize": 4096, "ino": 2 },
+            ],
+        ]);
+
+        assert_eq!(expected, export(SOURCE, &config));
+    }
+
+    #[test]
+    fn kb_allocated() {
+        let config = Config::from_args(&[
+            "--kb-allocated",
+            "--export-ncdu",
+            "mmdu.json",
+        ]);
+
+        let source = indoc! {"
+            1 1 0  4 1 drwxr-xr-x 4096 -- /data/test
+            2 1 0  0 1 -rw-r--r-- 1048576 -- /data/test/sparse
+        "};
+
+        let json = export(source, &config);
+        let sparse = &json[3][1];
+
+        assert_eq!("sparse", sparse["name"]);
+        assert_eq!(1_048_576, sparse["asize"]);
+        assert_eq!(0, sparse["dsize"]);
+    }
+}
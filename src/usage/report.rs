@@ -0,0 +1,640 @@
+/* * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * *
+ *                                                                           *
+ *  Copyright  (C)  2019-2024  Christian Krause                              *
+ *                                                                           *
+ *  Christian Krause  <christian.krause@idiv.de>                             *
+ *                                                                           *
+ * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * *
+ *                                                                           *
+ *  This file is part of mmdu.                                               *
+ *                                                                           *
+ *  mmdu is free software: you can redistribute it and/or modify             *
+ *  it under the terms of the GNU General Public License as published by     *
+ *  the Free Software Foundation, either version 3 of the license, or any    *
+ *  later version.                                                           *
+ *                                                                           *
+ *  mmdu is distributed in the hope that it will be useful, but              *
+ *  WITHOUT ANY WARRANTY; without even the implied warranty of               *
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU         *
+ *  General Public License for more details.                                 *
+ *                                                                           *
+ *  You should have received a copy of the GNU General Public License along  *
+ *  with mmdu. If not, see <http://www.gnu.org/licenses/>.                   *
+ *                                                                           *
+ * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * */
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::ops::Range;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+
+use anyhow::{Context, Result, anyhow};
+use bstr::ByteSlice;
+
+use crate::config::{ByteMode, Config, OnParseError, TimeMode};
+use crate::policy::{self, Entry, Layout};
+
+/// Policy report that can be read from several threads at once.
+pub trait Report: Sync {
+    fn len(&self) -> io::Result<u64>;
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+}
+
+impl Report for File {
+    fn len(&self) -> io::Result<u64> {
+        self.metadata().map(|metadata| metadata.len())
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        FileExt::read_at(self, buf, offset)
+    }
+}
+
+impl Report for [u8] {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.len() as u64)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let offset = usize::try_from(offset).unwrap_or(usize::MAX);
+        let mut rest = self.get(offset..).unwrap_or_default();
+        rest.read(buf)
+    }
+}
+
+/// Chunks smaller than this are not worth another thread.
+const MIN_CHUNK: u64 = 1 << 20;
+
+/// The result of parsing the report.
+pub struct Folded<S> {
+    /// The states of all threads.
+    pub states: Vec<S>,
+
+    /// Number of invalid lines that were skipped, see `--on-parse-error`.
+    pub skipped: u64,
+}
+
+/// Parses the report with up to `--threads` threads.
+///
+/// The report is split into chunks at arbitrary offsets, each thread then
+/// parses the lines starting within its chunk. Every thread folds its entries
+/// into its own state, which is created with `init`. The states of all threads
+/// are returned to be merged by the caller.
+pub fn fold<R, S, I, F>(
+    report: &R,
+    config: &Config,
+    init: I,
+    f: F,
+) -> Result<Folded<S>>
+where
+    R: Report + ?Sized,
+    S: Send,
+    I: Fn() -> Result<S> + Sync,
+    F: Fn(&mut S, &Entry) -> Result<()> + Sync,
+{
+    let skipped = AtomicU64::new(0);
+
+    let states = fold_with(report, config, init, f, |error, offset| {
+        skip(error, offset, config, &skipped)
+    })?;
+
+    Ok(Folded {
+        states,
+        skipped: skipped.into_inner(),
+    })
+}
+
+fn fold_with<R, S, I, F, E>(
+    report: &R,
+    config: &Config,
+    init: I,
+    f: F,
+    on_error: E,
+) -> Result<Vec<S>>
+where
+    R: Report + ?Sized,
+    S: Send,
+    I: Fn() -> Result<S> + Sync,
+    F: Fn(&mut S, &Entry) -> Result<()> + Sync,
+    E: Fn(anyhow::Error, u64) -> Result<()> + Sync,
+{
+    let len = report.len().context("reading length of policy report")?;
+    let chunks = chunks(len, config.threads, MIN_CHUNK);
+    let layout = Layout::from(config);
+
+    let abort = AtomicBool::new(false);
+
+    let fold_chunk = |range: Range<u64>| {
+        let mut state = init()?;
+
+        let result = lines(report, range, &abort, |offset, line| {
+            match Entry::parse(line, layout) {
+                Ok(entry) => f(&mut state, &entry),
+                Err(error) => on_error(error, offset),
+            }
+        });
+
+        if result.is_err() {
+            abort.store(true, Ordering::Relaxed);
+        }
+
+        result.map(|()| state)
+    };
+
+    if chunks.len() == 1 {
+        chunks.into_iter().map(fold_chunk).collect()
+    } else {
+        fold_parallel(chunks, fold_chunk)
+    }
+}
+
+fn fold_parallel<S, F>(
+    chunks: Vec<Range<u64>>,
+    fold_chunk: F,
+) -> Result<Vec<S>>
+where
+    S: Send,
+    F: Fn(Range<u64>) -> Result<S> + Sync,
+{
+    thread::scope(|scope| {
+        // ALLOW collect is necessary to spawn all threads before joining
+        #[allow(clippy::needless_collect)]
+        let handles = chunks
+            .into_iter()
+            .map(|range| scope.spawn(|| fold_chunk(range)))
+            .collect::<Vec<_>>();
+
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .map_err(|_| anyhow!("policy report thread panicked"))?
+            })
+            .collect()
+    })
+}
+
+/// Handles an invalid line at `offset` according to `--on-parse-error`.
+fn skip(
+    error: anyhow::Error,
+    offset: u64,
+    config: &Config,
+    skipped: &AtomicU64,
+) -> Result<()> {
+    let error = error.context("parsing line from policy report");
+
+    if config.on_parse_error == OnParseError::Fail {
+        return Err(error);
+    }
+
+    let count = skipped.fetch_add(1, Ordering::Relaxed) + 1;
+
+    if let Some(max) = config.max_parse_errors {
+        if count > max {
+            return Err(
+                error.context(format!("more than {max} invalid lines"))
+            );
+        }
+    }
+
+    if config.on_parse_error == OnParseError::Warn {
+        #[cfg(not(feature = "log"))]
+        eprintln!(
+            "{}: skipping line at byte offset {offset}: {error:#}",
+            clap::crate_name!()
+        );
+
+        #[cfg(feature = "log")]
+        log::warn!("skipping line at byte offset {offset}: {error:#}");
+    }
+
+    Ok(())
+}
+
+/// Splits `len` bytes into at most `threads` chunks of at least `min` bytes.
+fn chunks(len: u64, threads: usize, min: u64) -> Vec<Range<u64>> {
+    let threads = (threads as u64).clamp(1, (len / min).max(1));
+    let size = len.div_ceil(threads).max(1);
+
+    (0..threads)
+        .map(|i| (i * size).min(len)..((i + 1) * size).min(len))
+        .collect()
+}
+
+/// Calls `f` for every line starting within `range`, without terminator,
+/// along with its offset.
+fn lines<R: Report + ?Sized>(
+    report: &R,
+    range: Range<u64>,
+    abort: &AtomicBool,
+    mut f: impl FnMut(u64, &[u8]) -> Result<()>,
+) -> Result<()> {
+    // start at the previous byte, if that is a line terminator the chunk
+    // starts with a complete line, otherwise skip the partial line
+    let start = range.start.saturating_sub(1);
+
+    let mut reader = BufReader::with_capacity(
+        1 << 16,
+        Chunk {
+            report,
+            offset: start,
+        },
+    );
+
+    let mut offset = start;
+    let mut line = Vec::new();
+
+    if range.start > 0 {
+        offset += reader
+            .read_until(b'\n', &mut line)
+            .context("reading line from policy report")?
+            as u64;
+    }
+
+    while offset < range.end && !abort.load(Ordering::Relaxed) {
+        line.clear();
+
+        let n = reader
+            .read_until(b'\n', &mut line)
+            .context("reading line from policy report")?;
+
+        if n == 0 {
+            break;
+        }
+
+        let line_offset = offset;
+        offset += n as u64;
+
+        let line = line.strip_suffix(b"\n").unwrap_or(&line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+        f(line_offset, line).with_context(|| {
+            line_number(report, line_offset).map_or_else(
+                |_| format!("line at byte offset {line_offset}"),
+                |number| format!("line {number} at byte offset {line_offset}"),
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Returns the number of the line starting at `offset`, counting from one.
+///
+/// This reads the report up to `offset` again, it is meant only for error
+/// messages.
+fn line_number<R: Report + ?Sized>(
+    report: &R,
+    offset: u64,
+) -> io::Result<u64> {
+    let mut reader = BufReader::new(Chunk { report, offset: 0 }.take(offset));
+    let mut line = Vec::new();
+    let mut number = 1;
+
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        if line.ends_with(b"\n") {
+            number += 1;
+        }
+
+        line.clear();
+    }
+
+    Ok(number)
+}
+
+/// Reads a report sequentially from `offset` on.
+struct Chunk<'a, R: ?Sized> {
+    report: &'a R,
+    offset: u64,
+}
+
+impl<R: Report + ?Sized> Read for Chunk<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.report.read_at(buf, self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+// ----------------------------------------------------------------------------
+// saved reports
+// ----------------------------------------------------------------------------
+
+/// The start of the first line of a report saved with `--save-report`.
+const HEADER: &str = "# mmdu report:";
+
+/// Saves the policy report of `dir` to `file`, see `--save-report`.
+///
+/// The first line records the policy attributes of the SHOW columns and the
+/// DIR, so the report can be parsed without the options it was created with.
+pub fn save(
+    report: &Path,
+    file: &Path,
+    dir: &Path,
+    config: &Config,
+) -> Result<()> {
+    let mut source = File::open(report).context("opening policy report")?;
+    let mut target = File::create(file).context("creating file")?;
+
+    target
+        .write_all(&header(config, dir))
+        .context("writing header")?;
+    io::copy(&mut source, &mut target).context("copying policy report")?;
+
+    Ok(())
+}
+
+fn header(config: &Config, dir: &Path) -> Vec<u8> {
+    let mut header = columns(config).into_bytes();
+    header.extend_from_slice(b" -- ");
+
+    // like the policy engine, so the DIR can't break the line
+    for &byte in dir.as_os_str().as_bytes() {
+        if matches!(byte, b'%' | b'\n' | b'\r') {
+            header.extend(format!("%{byte:02X}").bytes());
+        } else {
+            header.push(byte);
+        }
+    }
+
+    header.push(b'\n');
+    header
+}
+
+fn columns(config: &Config) -> String {
+    let layout = Layout::from(config);
+
+    let mut columns =
+        format!("{HEADER} {}", config.byte_mode.policy_attribute());
+
+    if layout.other {
+        columns.push(' ');
+        columns.push_str(config.byte_mode.other().policy_attribute());
+    }
+
+    if let Some(time_mode) = config.time_mode {
+        columns.push(' ');
+        columns.push_str(time_mode.policy_attribute());
+    }
+
+    if layout.owner {
+        columns.push_str(" USER_ID");
+    }
+
+    columns
+}
+
+/// Returns `config` with the columns of a report saved with `config`, along
+/// with the DIR of the report.
+fn parse_header(line: &[u8], config: &Config) -> Result<(Config, PathBuf)> {
+    let not_saved = || anyhow!("not a report saved with --save-report");
+
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+
+    let (line, dir) = line.split_once_str(" -- ").ok_or_else(not_saved)?;
+    let line = str::from_utf8(line).map_err(|_| not_saved())?;
+
+    let attributes = line.strip_prefix(HEADER).ok_or_else(not_saved)?;
+
+    let dir = policy::decode(dir)
+        .map(|dir| PathBuf::from(OsString::from_vec(dir.into_owned())))
+        .filter(|dir| dir.is_absolute())
+        .ok_or_else(|| anyhow!("invalid DIR in header"))?;
+
+    let mut attributes = attributes.split_whitespace();
+
+    let byte_mode = attributes
+        .next()
+        .and_then(|attribute| {
+            [ByteMode::FileSize, ByteMode::KBAllocated]
+                .into_iter()
+                .find(|mode| mode.policy_attribute() == attribute)
+        })
+        .ok_or_else(|| anyhow!("policy attribute missing in header"))?;
+
+    let mut columns = config.columns;
+    columns.owner = false;
+
+    let mut saved = Config {
+        byte_mode,
+        time_mode: None,
+        allocation: false,
+        columns,
+        export_ncdu: None,
+        save_report: None,
+        ..config.clone()
+    };
+
+    for attribute in attributes {
+        let time_mode = [
+            TimeMode::Modification,
+            TimeMode::Access,
+            TimeMode::Change,
+            TimeMode::Creation,
+        ]
+        .into_iter()
+        .find(|mode| mode.policy_attribute() == attribute);
+
+        if attribute == byte_mode.other().policy_attribute() {
+            saved.allocation = true;
+        } else if attribute == "USER_ID" {
+            saved.columns.owner = true;
+        } else if time_mode.is_some() {
+            saved.time_mode = time_mode;
+        } else {
+            return Err(anyhow!("unknown column {attribute} in header"));
+        }
+    }
+
+    // the columns have a fixed order and may appear only once
+    if self::columns(&saved) != line {
+        return Err(anyhow!("invalid header: {line}"));
+    }
+
+    Ok((saved, dir))
+}
+
+/// A report saved with `--save-report`, without its header.
+pub struct Saved {
+    file: File,
+    offset: u64,
+    dir: PathBuf,
+}
+
+impl Saved {
+    /// Opens a saved report.
+    ///
+    /// Returns the report along with `config` changed to the columns of the
+    /// report.
+    pub fn open(file: &Path, config: &Config) -> Result<(Self, Config)> {
+        let file = File::open(file).context("opening report")?;
+
+        let mut line = Vec::new();
+        BufReader::new(Chunk {
+            report: &file,
+            offset: 0,
+        })
+        .take(1 << 16)
+        .read_until(b'\n', &mut line)
+        .context("reading header")?;
+
+        let (config, dir) = parse_header(&line, config)?;
+
+        let offset = line.len() as u64;
+
+        Ok((Self { file, offset, dir }, config))
+    }
+
+    /// Returns the DIR the report was saved for.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Report for Saved {
+    fn len(&self) -> io::Result<u64> {
+        Report::len(&self.file).map(|len| len.saturating_sub(self.offset))
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        Report::read_at(&self.file, buf, offset + self.offset)
+    }
+}
+
+// ----------------------------------------------------------------------------
+// tests
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SOURCE: &[u8] = b"first\nsecond line\n\nfourth\r\nfifth";
+
+    fn collect(range: Range<u64>) -> Vec<Vec<u8>> {
+        let abort = AtomicBool::new(false);
+        let mut result = vec![];
+
+        lines(SOURCE, range, &abort, |_, line| {
+            result.push(line.to_vec());
+            Ok(())
+        })
+        .unwrap();
+
+        result
+    }
+
+    #[test]
+    fn split_anywhere() {
+        let len = SOURCE.len() as u64;
+        let expected = collect(0..len);
+
+        assert_eq!(
+            expected,
+            [&b"first"[..], b"second line", b"", b"fourth", b"fifth"]
+        );
+
+        for split in 0..=len {
+            let mut result = collect(0..split);
+            result.extend(collect(split..len));
+            assert_eq!(expected, result, "split at {split}");
+        }
+    }
+
+    #[test]
+    fn error_location() {
+        let abort = AtomicBool::new(false);
+        let len = SOURCE.len() as u64;
+
+        for split in 0..=len {
+            let error = lines(SOURCE, split..len, &abort, |_, line| {
+                if line == b"fourth" {
+                    Err(anyhow!("invalid"))
+                } else {
+                    Ok(())
+                }
+            });
+
+            if split <= 19 {
+                let error = format!("{:#}", error.unwrap_err());
+                assert_eq!(error, "line 4 at byte offset 19: invalid");
+            } else {
+                assert!(error.is_ok(), "split at {split}");
+            }
+        }
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let dir = Path::new("/data/new\nline 100%");
+
+        for args in [
+            &[][..],
+            &["--kb-allocated", "--allocation"],
+            &["--time=ctime", "--columns", "owner"],
+            &["--export-ncdu", "mmdu.json", "--time"],
+        ] {
+            let config = Config::from_args(args);
+            let line = header(&config, dir);
+            let (saved, saved_dir) = parse_header(&line, &config).unwrap();
+
+            assert_eq!(
+                Layout::from(&config),
+                Layout::from(&saved),
+                "{args:?}"
+            );
+            assert_eq!(config.byte_mode, saved.byte_mode);
+            assert_eq!(config.time_mode, saved.time_mode);
+            assert_eq!(dir, saved_dir);
+        }
+    }
+
+    #[test]
+    fn header_from_other_options() {
+        let config = Config::from_args(&["--columns", "owner"]);
+        let line = b"# mmdu report: KB_ALLOCATED FILE_SIZE MODIFICATION_TIME \
+                     -- /data/test";
+        let (saved, dir) = parse_header(line, &config).unwrap();
+
+        assert_eq!(ByteMode::KBAllocated, saved.byte_mode);
+        assert_eq!(Some(TimeMode::Modification), saved.time_mode);
+        assert!(saved.allocation);
+        assert!(!saved.columns.owner);
+        assert_eq!(Path::new("/data/test"), dir);
+    }
+
+    #[test]
+    fn header_invalid() {
+        let config = Config::from_args(&[]);
+
+        for line in [
+            "1 1 0  4096 1 drwxr-xr-x -- /data/test",
+            "# mmdu report: -- /data/test",
+            "# mmdu report: FILE_SIZE",
+            "# mmdu report: FILE_SIZE -- data/test",
+            "# mmdu report: FILE_SIZE -- /data/%ZZ",
+            "# mmdu report: FILE_SIZE SIZE -- /data/test",
+            "# mmdu report: FILE_SIZE USER_ID MODIFICATION_TIME -- /data/test",
+            "# mmdu report: FILE_SIZE USER_ID USER_ID -- /data/test",
+        ] {
+            let error = parse_header(line.as_bytes(), &config);
+            assert!(error.is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn chunk_sizes() {
+        assert_eq!(vec![0..0], chunks(0, 4, 10));
+        assert_eq!(vec![0..9], chunks(9, 4, 10));
+        assert_eq!(vec![0..10, 10..20], chunks(20, 4, 10));
+        assert_eq!(vec![0..34, 34..68, 68..100], chunks(100, 3, 10));
+    }
+}
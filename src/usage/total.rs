@@ -23,50 +23,95 @@
  *                                                                           *
  * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * */
 
-use std::collections::HashMap;
-use std::io::{BufReader, Read};
+use std::path::Path;
 
-use anyhow::{Context, Result};
-use bstr::io::BufReadExt;
+use anyhow::Result;
 
-use crate::policy::Entry;
-use crate::usage::Acc;
+use crate::config::Config;
+use crate::usage::links::HardLinks;
+use crate::usage::report::{self, Report};
+use crate::usage::{Acc, DirTotal, GrandTotal};
 
-pub fn sum(report: impl Read, count_links: bool) -> Result<Acc> {
-    let mut sum = Acc::default();
-    let mut hard_links: HashMap<String, u64> = HashMap::new();
+/// State of each thread parsing the report.
+struct State {
+    sum: Acc,
 
-    for line in BufReader::new(report).byte_lines() {
-        let line = line.context("reading line from policy report")?;
+    hard_links: HardLinks,
+    total: Option<DirTotal>,
+}
 
-        let entry = Entry::try_from(line.as_slice())
-            .context("parsing line from policy report")?;
+pub fn sum<R: Report + ?Sized>(
+    dir: &Path,
+    report: &R,
+    device: u64,
+    config: &Config,
+    mut total: Option<&mut GrandTotal>,
+) -> Result<Acc> {
+    let count_links = config.count_links;
+    let with_total = total.is_some();
 
-        let bytes = entry.bytes()?;
+    let folded = report::fold(
+        report,
+        config,
+        || {
+            Ok(State {
+                sum: Acc::default(),
+                hard_links: HardLinks::for_thread(config),
+                total: with_total.then(|| DirTotal::new(config)),
+            })
+        },
+        |state, entry| {
+            let acc = Acc::from_entry(entry, config);
+            let id = entry.id(device);
 
-        if count_links {
-            sum += bytes;
-            continue;
-        }
+            // the sum is the one of the DIR, so is the owner
+            if entry.uid.is_some() && entry.path() == dir {
+                state.sum.owner = entry.uid;
+            }
 
-        let nlink = entry.nlink_str()?;
+            if let Some(total) = &mut state.total {
+                total.add(entry, id, acc, count_links)?;
+            }
 
-        if nlink == "1" {
-            sum += bytes;
-            continue;
-        }
+            if count_links || !entry.is_hard_link() {
+                state.sum += acc;
+            } else {
+                state.hard_links.insert(id, 0, acc)?;
+            }
+
+            Ok(())
+        },
+    )?;
+
+    let mut sum = Acc::default();
+    let mut hard_links = HardLinks::for_thread(config);
+    let mut dir_total = DirTotal::new(config);
 
-        let inode = entry.inode_str()?;
-        let inode = hard_links
-            .entry(inode.to_owned())
-            .and_modify(|c| *c += 1)
-            .or_insert(1);
+    for state in folded.states {
+        sum += state.sum;
+        hard_links.merge(state.hard_links)?;
 
-        if *inode == 1 {
-            sum += bytes;
+        if let Some(total) = state.total {
+            dir_total.merge(total)?;
         }
     }
 
+    GrandTotal::finish_links(
+        total.as_deref_mut(),
+        hard_links,
+        |_, _, acc| {
+            sum += acc;
+        },
+    )?;
+
+    sum.skipped = folded.skipped;
+
+    dir_total.acc.skipped += folded.skipped;
+
+    if let Some(total) = total {
+        total.add_pending(dir_total)?;
+    }
+
     Ok(sum)
 }
 
@@ -79,39 +124,281 @@ mod test {
     use indoc::indoc;
 
     use super::*;
+    use crate::time::Timestamp;
+    use crate::usage::{Sizes, Types};
 
     const SOURCE: &str = indoc! {"
-        1 1 0  4096 1 -- /data/test
-        1 1 0  1024 3 -- /data/test/foo
-        1 1 0  1024 3 -- /data/test/bar
-        1 1 0  1024 3 -- /data/test/baz
-        2 1 0  1024 2 -- /data/test/other
+        1 1 0  4096 1 drwxr-xr-x -- /data/test
+        1 1 0  1024 3 -rw-r--r-- -- /data/test/foo
+        1 1 0  1024 3 -rw-r--r-- -- /data/test/bar
+        1 1 0  1024 3 -rw-r--r-- -- /data/test/baz
+        2 1 0  1024 2 -rw-r--r-- -- /data/test/other
+    "};
+
+    const SOURCE_WITH_TIME: &str = indoc! {"
+        1 1 0  4096 1 drwxr-xr-x 2024-01-01 00:00:00 -- /data/test
+        2 1 0  1024 1 -rw-r--r-- 2024-03-01 12:00:00 -- /data/test/foo
+        3 1 0  1024 1 -rw-r--r-- 2023-12-24 18:00:00 -- /data/test/bar
+    "};
+
+    const SOURCE_OTHER: &str = indoc! {"
+        3 1 0  4096 1 drwxr-xr-x -- /data/other
+        2 1 0  1024 2 -rw-r--r-- -- /data/other/other
+        4 1 0  1024 1 -rw-r--r-- -- /data/other/foo
+    "};
+
+    const SOURCE_SNAPSHOT: &str = indoc! {"
+        1 1 0  4096 1 drwxr-xr-x -- /data/test
+        2 1 0  1024 2 -rw-r--r-- -- /data/test/foo
+        2 1 0  1024 2 -rw-r--r-- -- /data/test/bar
+        2 1 1  1024 2 -rw-r--r-- -- /data/test/.snapshots/1/foo
+        2 1 1  1024 2 -rw-r--r-- -- /data/test/.snapshots/1/bar
+    "};
+
+    const SOURCE_TYPES: &str = indoc! {"
+        1 1 0  4096 2 drwxr-xr-x -- /data/test
+        2 1 0  1024 2 -rw-r--r-- -- /data/test/foo
+        2 1 0  1024 2 -rw-r--r-- -- /data/test/bar
+        3 1 0  8 1 lrwxrwxrwx -- /data/test/link
+        4 1 0  0 1 srwxr-xr-x -- /data/test/socket
+    "};
+
+    const SOURCE_ALLOCATION: &str = indoc! {"
+        1 1 0  4096 2 drwxr-xr-x 4 -- /data/test
+        2 1 0  1048576 1 -rw-r--r-- 0 -- /data/test/sparse
+        3 1 0  100 2 -rw-r--r-- 16 -- /data/test/small
+        3 1 0  100 2 -rw-r--r-- 16 -- /data/test/link
     "};
 
     const INVALID: &str = indoc! {"
-        1 1 0  4096 1 -- /data/test
-        1 1 0  1024 2 -- /data/test/foo
+        1 1 0  4096 1 drwxr-xr-x -- /data/test
+        1 1 0  1024 2 -rw-r--r-- -- /data/test/foo
         1 1 0  3 -- /data/test/bar
     "};
 
+    /// Returns the DIR of `source`, i.e. the path of its first line.
+    fn dir(source: &str) -> &Path {
+        let line = source.lines().next().unwrap_or_default();
+        Path::new(line.split_once(" -- ").map_or("", |(_, path)| path))
+    }
+
+    /// Returns the sum of the DIR of `source`.
+    fn run(args: &[&str], source: &str) -> Result<Acc> {
+        let config = Config::from_args(args);
+        sum(dir(source), source.as_bytes(), 0, &config, None)
+    }
+
+    /// Returns the sum of the DIR of `source` on `device`, which is pending
+    /// in `total`.
+    fn add(
+        total: &mut GrandTotal,
+        config: &Config,
+        source: &str,
+        device: u64,
+    ) -> Result<Acc> {
+        sum(dir(source), source.as_bytes(), device, config, Some(total))
+    }
+
     #[test]
     fn parse_hardlinks_once() {
-        let sum = sum(SOURCE.as_bytes(), false).unwrap();
-        assert_eq!(Acc::from((3, 6144)), sum);
+        assert_eq!(Acc::from((3, 6144)), run(&[], SOURCE).unwrap());
     }
 
     #[test]
     fn parse_hardlinks_many() {
-        let sum = sum(SOURCE.as_bytes(), true).unwrap();
+        let sum = run(&["--count-links"], SOURCE).unwrap();
         assert_eq!(Acc::from((5, 8192)), sum);
     }
 
+    #[test]
+    fn grand_total() {
+        let config = Config::from_args(&[]);
+        let mut total = GrandTotal::new(&config);
+
+        let a = add(&mut total, &config, SOURCE, 0).unwrap();
+        total.commit().unwrap();
+        let b = add(&mut total, &config, SOURCE_OTHER, 0).unwrap();
+        total.commit().unwrap();
+
+        assert_eq!(Acc::from((3, 6144)), a);
+        assert_eq!(Acc::from((3, 6144)), b);
+        assert_eq!(Acc::from((5, 11264)), total.finish().unwrap());
+    }
+
+    #[test]
+    fn grand_total_spilled() {
+        let config = Config::from_args(&["--link-memory=1"]);
+        let mut total = GrandTotal::new(&config);
+
+        add(&mut total, &config, SOURCE, 0).unwrap();
+        total.commit().unwrap();
+        add(&mut total, &config, SOURCE_OTHER, 0).unwrap();
+        total.commit().unwrap();
+
+        assert_eq!(Acc::from((5, 11264)), total.finish().unwrap());
+    }
+
+    #[test]
+    fn parse_snapshots() {
+        assert_eq!(Acc::from((3, 6144)), run(&[], SOURCE_SNAPSHOT).unwrap());
+    }
+
+    #[test]
+    fn grand_total_devices() {
+        let config = Config::from_args(&[]);
+        let mut total = GrandTotal::new(&config);
+
+        add(&mut total, &config, SOURCE, 1).unwrap();
+        total.commit().unwrap();
+        add(&mut total, &config, SOURCE_OTHER, 2).unwrap();
+        total.commit().unwrap();
+
+        assert_eq!(Acc::from((6, 12288)), total.finish().unwrap());
+    }
+
+    #[test]
+    fn grand_total_discard() {
+        let config = Config::from_args(&[]);
+        let mut total = GrandTotal::new(&config);
+
+        add(&mut total, &config, SOURCE, 0).unwrap();
+        total.commit().unwrap();
+        assert!(add(&mut total, &config, INVALID, 0).is_err());
+        total.discard();
+
+        assert_eq!(Acc::from((3, 6144)), total.finish().unwrap());
+    }
+
+    #[test]
+    fn dedupe_across_dirs() {
+        for args in [
+            &["--dedupe-across-dirs"][..],
+            &["--dedupe-across-dirs", "--link-memory=1"],
+        ] {
+            let config = Config::from_args(args);
+            let mut total = GrandTotal::new(&config);
+
+            let a = add(&mut total, &config, SOURCE, 0).unwrap();
+            total.commit().unwrap();
+            let b = add(&mut total, &config, SOURCE_OTHER, 0).unwrap();
+            total.commit().unwrap();
+
+            assert_eq!(Acc::from((3, 6144)), a);
+            assert_eq!(Acc::from((2, 5120)), b);
+
+            let mut sum = a;
+            sum += b;
+            assert_eq!(sum, total.finish().unwrap());
+        }
+    }
+
+    #[test]
+    fn dedupe_across_dirs_discard() {
+        let config = Config::from_args(&["--dedupe-across-dirs"]);
+        let mut total = GrandTotal::new(&config);
+
+        let a = add(&mut total, &config, SOURCE, 0).unwrap();
+        total.discard();
+        let b = add(&mut total, &config, SOURCE_OTHER, 0).unwrap();
+        total.commit().unwrap();
+
+        assert_eq!(Acc::from((3, 6144)), a);
+        assert_eq!(Acc::from((3, 6144)), b);
+    }
+
+    #[test]
+    fn parse_types() {
+        let without = run(&[], SOURCE_TYPES).unwrap();
+        assert_eq!(Acc::from((4, 5128)), without);
+
+        let with =
+            run(&["--types", "--link-memory", "1"], SOURCE_TYPES).unwrap();
+
+        let types = Types {
+            files: 1,
+            dirs: 1,
+            symlinks: 1,
+            other: 1,
+        };
+
+        assert_eq!(
+            Acc {
+                types,
+                ..Acc::from((4, 5128))
+            },
+            with
+        );
+    }
+
+    #[test]
+    fn parse_allocation() {
+        let args = ["--allocation", "--link-memory", "1"];
+        let sum = run(&args, SOURCE_ALLOCATION).unwrap();
+
+        let sizes = Sizes {
+            apparent: 1_052_772,
+            allocated: 20,
+        };
+
+        assert_eq!(
+            Acc {
+                sizes,
+                ..Acc::from((3, 1_052_772))
+            },
+            sum
+        );
+    }
+
+    #[test]
+    fn parse_time() {
+        let sum = run(&["--time"], SOURCE_WITH_TIME).unwrap();
+
+        assert_eq!(
+            sum.time,
+            Timestamp::parse("2024-03-01", "12:00:00"),
+            "newest time should be kept"
+        );
+    }
+
     #[test]
     fn parse_invalid() {
-        let result = sum(INVALID.as_bytes(), false);
+        let result = run(&[], INVALID);
         assert!(result.is_err());
 
         let error = format!("{:#?}", result.unwrap_err());
         assert!(error.contains("/data/test/bar"));
+        assert!(error.contains("line 3 at byte offset 82"));
+    }
+
+    #[test]
+    fn parse_invalid_skip() {
+        let expected = Acc {
+            skipped: 1,
+            ..Acc::from((2, 5120))
+        };
+
+        for mode in ["skip", "warn"] {
+            let config = Config::from_args(&["--on-parse-error", mode]);
+            let mut total = GrandTotal::new(&config);
+
+            let sum = add(&mut total, &config, INVALID, 0).unwrap();
+            total.commit().unwrap();
+
+            assert_eq!(expected, sum);
+            assert_eq!(expected, total.finish().unwrap());
+        }
+    }
+
+    #[test]
+    fn parse_invalid_budget() {
+        let args = ["--on-parse-error=skip", "--max-parse-errors=1"];
+        assert!(run(&args, INVALID).is_ok());
+
+        let args = ["--on-parse-error=skip", "--max-parse-errors=0"];
+        let result = run(&args, INVALID);
+
+        let error = format!("{:#}", result.unwrap_err());
+        assert!(error.contains("more than 0 invalid lines"));
+        assert!(error.contains("line 3 at byte offset 82"));
     }
 }
@@ -0,0 +1,251 @@
+/* * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * *
+ *                                                                           *
+ *  Copyright  (C)  2019-2024  Christian Krause                              *
+ *                                                                           *
+ *  Christian Krause  <christian.krause@idiv.de>                             *
+ *                                                                           *
+ * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * *
+ *                                                                           *
+ *  This file is part of mmdu.                                               *
+ *                                                                           *
+ *  mmdu is free software: you can redistribute it and/or modify             *
+ *  it under the terms of the GNU General Public License as published by     *
+ *  the Free Software Foundation, either version 3 of the license, or any    *
+ *  later version.                                                           *
+ *                                                                           *
+ *  mmdu is distributed in the hope that it will be useful, but              *
+ *  WITHOUT ANY WARRANTY; without even the implied warranty of               *
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU         *
+ *  General Public License for more details.                                 *
+ *                                                                           *
+ *  You should have received a copy of the GNU General Public License along  *
+ *  with mmdu. If not, see <http://www.gnu.org/licenses/>.                   *
+ *                                                                           *
+ * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * * */
+
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::ops::{Index, IndexMut};
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::policy::{Entry, Kind};
+use crate::usage::{Acc, Sizes};
+
+/// Directory tree of a DIR, interned by path components.
+///
+/// Nodes are referred to by index, the root node being the DIR itself.
+#[derive(Debug)]
+pub struct Tree {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug)]
+pub struct Node {
+    name: OsString,
+    parent: Option<u32>,
+    children: BTreeMap<OsString, u32>,
+    pub acc: Acc,
+
+    /// Whether this is a directory, only tracked with `--empty-dirs`.
+    pub dir: bool,
+
+    /// The attributes of the entry itself, only with `--export-ncdu`.
+    pub item: Option<Item>,
+}
+
+/// The attributes of a single file or directory.
+#[derive(Clone, Copy, Debug)]
+pub struct Item {
+    pub inode: u64,
+    pub nlink: u64,
+    pub kind: Kind,
+    pub sizes: Sizes,
+}
+
+impl Tree {
+    pub const ROOT: u32 = 0;
+
+    pub fn new(root: &Path) -> Self {
+        let root = Node {
+            name: root.as_os_str().to_owned(),
+            parent: None,
+            children: BTreeMap::new(),
+            acc: Acc::default(),
+            dir: false,
+            item: None,
+        };
+
+        Self { nodes: vec![root] }
+    }
+
+    /// Returns the child of `parent` with `name`, if it exists.
+    pub fn get(&self, parent: u32, name: &OsStr) -> Option<u32> {
+        self[parent].children.get(name).copied()
+    }
+
+    /// Returns the child of `parent` with `name`, inserting it if necessary.
+    pub fn child(&mut self, parent: u32, name: &OsStr) -> u32 {
+        if let Some(child) = self.get(parent, name) {
+            return child;
+        }
+
+        let child = u32::try_from(self.nodes.len()).expect("too many nodes");
+
+        self.nodes.push(Node {
+            name: name.to_owned(),
+            parent: Some(parent),
+            children: BTreeMap::new(),
+            acc: Acc::default(),
+            dir: false,
+            item: None,
+        });
+
+        self[parent].children.insert(name.to_owned(), child);
+
+        child
+    }
+
+    /// Merges `other`, e.g. the tree of another thread, into this tree.
+    ///
+    /// The sums of nodes with the same path are added up.
+    pub fn merge(&mut self, other: Self) {
+        // in insertion order, parents are merged before their children
+        let mut nodes = Vec::with_capacity(other.nodes.len());
+
+        for node in other.nodes {
+            let index = node.parent.map_or(Self::ROOT, |parent| {
+                self.child(nodes[parent as usize], &node.name)
+            });
+
+            let merged = &mut self[index];
+            merged.acc += node.acc;
+            merged.dir |= node.dir;
+            merged.item = merged.item.or(node.item);
+
+            nodes.push(index);
+        }
+    }
+
+    /// Returns the full path of `node`.
+    pub fn path(&self, node: u32) -> PathBuf {
+        let mut names = vec![];
+        let mut node = Some(node);
+
+        while let Some(index) = node {
+            names.push(self[index].name.as_os_str());
+            node = self[index].parent;
+        }
+
+        names.into_iter().rev().collect()
+    }
+
+    /// Returns the depth of `node` below the root.
+    pub fn depth(&self, mut node: u32) -> usize {
+        let mut depth = 0;
+
+        while let Some(parent) = self[node].parent {
+            node = parent;
+            depth += 1;
+        }
+
+        depth
+    }
+
+    /// Returns the children of `node`, ordered by name.
+    pub fn children(&self, node: u32) -> impl Iterator<Item = u32> + '_ {
+        self[node].children.values().copied()
+    }
+
+    /// Returns all nodes in insertion order, i.e. parents before children.
+    pub fn nodes(&self) -> impl Iterator<Item = (u32, &Node)> {
+        (0..).zip(&self.nodes)
+    }
+}
+
+impl Node {
+    /// Returns the last path component, or the DIR itself for the root.
+    pub fn name(&self) -> &OsStr {
+        &self.name
+    }
+}
+
+impl Item {
+    pub const fn new(entry: &Entry, config: &Config) -> Self {
+        Self {
+            inode: entry.inode,
+            nlink: entry.nlink,
+            kind: entry.kind(),
+            sizes: Acc::from_entry(entry, config).sizes,
+        }
+    }
+}
+
+impl Index<u32> for Tree {
+    type Output = Node;
+
+    fn index(&self, index: u32) -> &Node {
+        &self.nodes[index as usize]
+    }
+}
+
+impl IndexMut<u32> for Tree {
+    fn index_mut(&mut self, index: u32) -> &mut Node {
+        &mut self.nodes[index as usize]
+    }
+}
+
+// ----------------------------------------------------------------------------
+// tests
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn intern() {
+        let mut tree = Tree::new(Path::new("/data/test"));
+
+        let a = tree.child(Tree::ROOT, OsStr::new("a"));
+        let b = tree.child(Tree::ROOT, OsStr::new("b"));
+        let c = tree.child(a, OsStr::new("c"));
+
+        assert_ne!(a, b);
+        assert_eq!(a, tree.child(Tree::ROOT, OsStr::new("a")));
+        assert_eq!(c, tree.child(a, OsStr::new("c")));
+        assert_ne!(c, tree.child(b, OsStr::new("c")));
+
+        assert_eq!(Path::new("/data/test"), tree.path(Tree::ROOT));
+        assert_eq!(Path::new("/data/test/a/c"), tree.path(c));
+
+        assert_eq!(0, tree.depth(Tree::ROOT));
+        assert_eq!(1, tree.depth(b));
+        assert_eq!(2, tree.depth(c));
+    }
+
+    #[test]
+    fn merge() {
+        let mut tree = Tree::new(Path::new("/data/test"));
+        let a = tree.child(Tree::ROOT, OsStr::new("a"));
+        tree[a].acc.inodes = 1;
+
+        let mut other = Tree::new(Path::new("/data/test"));
+        let b = other.child(Tree::ROOT, OsStr::new("b"));
+        let c = other.child(b, OsStr::new("c"));
+        let d = other.child(Tree::ROOT, OsStr::new("a"));
+        other[c].acc.inodes = 2;
+        other[d].acc.inodes = 3;
+        other[d].dir = true;
+
+        tree.merge(other);
+
+        assert_eq!(4, tree[a].acc.inodes);
+        assert!(tree[a].dir);
+
+        let b = tree.get(Tree::ROOT, OsStr::new("b")).unwrap();
+        let c = tree.get(b, OsStr::new("c")).unwrap();
+        assert_eq!(2, tree[c].acc.inodes);
+        assert_eq!(Path::new("/data/test/b/c"), tree.path(c));
+    }
+}